@@ -0,0 +1,182 @@
+use core::{ptr::NonNull, str::FromStr};
+
+use fdt_parser::Fdt;
+use log::debug;
+use somehal::boot_info;
+use spin::Once;
+
+static CMDLINE: Once<Cmdline> = Once::new();
+
+/// The kernel command line, taken from `/chosen/bootargs`.
+///
+/// Parameters are separated by whitespace and have the form `key`, `key=value`
+/// or `key="value with spaces"`. Everything after a lone `--` is left to the
+/// kernel and not treated as platform parameters.
+///
+/// The platform itself consumes:
+/// - `mem=<size>`: caps the RAM used;
+/// - `maxcpus=<n>`: caps the CPUs brought up;
+/// - `earlycon=<uart>,[<io type>,]<addr>`: picks the console UART by its
+///   register address instead of `/chosen/stdout-path`, if it is the UART
+///   the loader mapped for early output.
+#[derive(Debug, Clone, Copy)]
+pub struct Cmdline {
+    raw: &'static str,
+}
+
+impl Cmdline {
+    const fn new(raw: &'static str) -> Self {
+        Self { raw }
+    }
+
+    /// Returns the raw command line.
+    pub fn as_str(&self) -> &'static str {
+        self.raw
+    }
+
+    /// Iterates over the parameters as `(key, value)` pairs, `value` being
+    /// `None` for bare flags.
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, Option<&'static str>)> {
+        Params { rest: self.raw }
+    }
+
+    /// Iterates over the values of every occurrence of `key`, e.g. repeated
+    /// `memmap=` parameters.
+    pub fn get_all(&self, key: &str) -> impl Iterator<Item = &'static str> {
+        self.iter()
+            .filter(move |&(k, _)| k == key)
+            .filter_map(|(_, v)| v)
+    }
+
+    /// Returns the value of `key`. The last occurrence wins.
+    pub fn get(&self, key: &str) -> Option<&'static str> {
+        self.get_all(key).last()
+    }
+
+    /// Returns `true` if `key` is present, with or without a value.
+    pub fn has(&self, key: &str) -> bool {
+        self.iter().any(|(k, _)| k == key)
+    }
+
+    /// Returns the boolean value of `key`.
+    ///
+    /// A bare flag is `true`; values accept `1`/`0`, `y`/`n`, `yes`/`no`,
+    /// `on`/`off` and `true`/`false`.
+    pub fn get_bool(&self, key: &str) -> Option<bool> {
+        let (_, value) = self.iter().filter(|&(k, _)| k == key).last()?;
+        match value {
+            None => Some(true),
+            Some("1" | "y" | "Y" | "yes" | "on" | "true") => Some(true),
+            Some("0" | "n" | "N" | "no" | "off" | "false") => Some(false),
+            Some(_) => None,
+        }
+    }
+
+    /// Returns the value of `key` as an integer, decimal or `0x`-prefixed hex.
+    pub fn get_usize(&self, key: &str) -> Option<usize> {
+        parse_usize(self.get(key)?)
+    }
+
+    /// Returns the value of `key` as a size with an optional `K`/`M`/`G`/`T`
+    /// suffix, as in `mem=512M`.
+    pub fn get_size(&self, key: &str) -> Option<usize> {
+        parse_size(self.get(key)?)
+    }
+
+    /// Returns the value of `key` parsed as `T`.
+    pub fn get_parsed<T: FromStr>(&self, key: &str) -> Option<T> {
+        self.get(key)?.parse().ok()
+    }
+}
+
+struct Params {
+    rest: &'static str,
+}
+
+impl Iterator for Params {
+    type Item = (&'static str, Option<&'static str>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let s = self.rest.trim_start();
+        if s.is_empty() {
+            return None;
+        }
+
+        let mut quoted = false;
+        let end = s
+            .char_indices()
+            .find(|&(_, c)| {
+                if c == '"' {
+                    quoted = !quoted;
+                }
+                !quoted && c.is_whitespace()
+            })
+            .map_or(s.len(), |(i, _)| i);
+        let (param, rest) = s.split_at(end);
+        if param == "--" {
+            self.rest = "";
+            return None;
+        }
+        self.rest = rest;
+
+        Some(match param.split_once('=') {
+            Some((k, v)) => (k, Some(unquote(v))),
+            None => (unquote(param), None),
+        })
+    }
+}
+
+fn unquote(s: &str) -> &str {
+    s.strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .unwrap_or(s)
+}
+
+/// Parses a decimal or `0x`-prefixed hexadecimal integer.
+pub fn parse_usize(s: &str) -> Option<usize> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// Parses a size such as `0x8000000`, `512M` or `4g`.
+pub fn parse_size(s: &str) -> Option<usize> {
+    let (num, shift) = match s.as_bytes().last()? {
+        b'k' | b'K' => (&s[..s.len() - 1], 10),
+        b'm' | b'M' => (&s[..s.len() - 1], 20),
+        b'g' | b'G' => (&s[..s.len() - 1], 30),
+        b't' | b'T' => (&s[..s.len() - 1], 40),
+        _ => (s, 0),
+    };
+    parse_usize(num)?.checked_mul(1 << shift)
+}
+
+/// Parses the command line out of the device tree at `fdt_paddr`, falling
+/// back to the one handed over by the boot stub if it is 0.
+///
+/// It runs before the memory layout is set up, so the device tree is reached
+/// the way the boot stub maps it, as the early console does.
+pub(crate) fn setup(fdt_paddr: usize) {
+    let cmdline = CMDLINE.call_once(|| {
+        let ptr = match fdt_paddr {
+            0 => boot_info().fdt,
+            paddr => NonNull::new(somehal::mem::phys_to_virt(paddr)),
+        };
+        let fdt = ptr.and_then(|ptr| Fdt::from_ptr(ptr).ok());
+        Cmdline::new(
+            fdt.and_then(|fdt| fdt.chosen())
+                .and_then(|chosen| chosen.bootargs())
+                .unwrap_or_default(),
+        )
+    });
+    debug!("kernel command line: {:?}", cmdline.as_str());
+}
+
+/// Returns the kernel command line.
+///
+/// It is empty if the device tree has no `/chosen/bootargs`.
+pub fn cmdline() -> &'static Cmdline {
+    static EMPTY: Cmdline = Cmdline::new("");
+    CMDLINE.get().unwrap_or(&EMPTY)
+}
@@ -0,0 +1,381 @@
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use any_uart::{Receiver, Sender};
+use axplat::console::ConsoleIf;
+use fdt_parser::{Fdt, Node};
+use heapless::Deque;
+use memory_addr::MemoryAddr;
+use somehal::boot_info;
+use spin::{Mutex, Once};
+
+use self::uart::RawUart;
+
+mod uart;
+
+/// Size of the console receive ring buffer.
+const RX_BUF_SIZE: usize = 1024;
+/// Size of the console transmit ring buffer.
+const TX_BUF_SIZE: usize = 4096;
+/// How many times [`emergency_write`] tries the console lock before it gives
+/// up on the holder.
+const EMERGENCY_LOCK_TRIES: usize = 1 << 20;
+
+static TX: Mutex<Option<Output>> = Mutex::new(None);
+static RX: Mutex<Option<Input>> = Mutex::new(None);
+static UART: Once<RawUart> = Once::new();
+#[cfg(feature = "irq")]
+static IRQ: Once<usize> = Once::new();
+/// Set while some CPU is polling the transmit buffer out to the UART.
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+/// Receive side of the console: the UART receiver and the bytes already taken
+/// out of its FIFO but not yet consumed by `read_bytes`.
+struct Input {
+    rx: Receiver,
+    buf: Deque<u8, RX_BUF_SIZE>,
+    /// The RX interrupt is masked because `buf` is full.
+    throttled: bool,
+}
+
+impl Input {
+    /// Moves the bytes pending in the UART FIFO into the ring buffer.
+    fn fill(&mut self) {
+        while !self.buf.is_full() {
+            match self.rx.read() {
+                Ok(c) => {
+                    let _ = self.buf.push_back(c);
+                }
+                Err(_) => return,
+            }
+        }
+        // Leave the rest in the FIFO instead of dropping it, and keep the
+        // level-triggered RX interrupt quiet until there is room again.
+        if irq_driven()
+            && let Some(uart) = UART.get()
+        {
+            uart.set_rx_irq(false);
+            self.throttled = true;
+        }
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        let c = self.buf.pop_front();
+        if self.throttled {
+            if let Some(uart) = UART.get() {
+                uart.set_rx_irq(true);
+            }
+            self.throttled = false;
+        }
+        c
+    }
+}
+
+/// Transmit side of the console: the UART sender and the bytes written by
+/// `write_bytes` that the UART has not accepted yet.
+struct Output {
+    tx: Sender,
+    buf: Deque<u8, TX_BUF_SIZE>,
+}
+
+impl Output {
+    /// Queues `c`, pushing bytes out synchronously only if the buffer is full.
+    fn push(&mut self, c: u8) {
+        while self.buf.is_full() {
+            self.drain();
+            core::hint::spin_loop();
+        }
+        let _ = self.buf.push_back(c);
+    }
+
+    /// Hands buffered bytes to the UART until its FIFO is full.
+    ///
+    /// Returns `true` if the buffer is empty afterwards.
+    fn drain(&mut self) -> bool {
+        while let Some(&c) = self.buf.front() {
+            if self.tx.write(c).is_err() {
+                return false;
+            }
+            self.buf.pop_front();
+        }
+        true
+    }
+
+    /// Writes `c` right away, after everything already buffered.
+    fn write_sync(&mut self, c: u8) {
+        while !self.drain() {
+            core::hint::spin_loop();
+        }
+        let _ = any_uart::block!(self.tx.write(c));
+    }
+}
+
+/// Calls `f` for every byte of `bytes`, turning `\n` into `\r\n`.
+fn for_each_out(bytes: &[u8], mut f: impl FnMut(u8)) {
+    for &c in bytes {
+        if c == b'\n' {
+            f(b'\r');
+        }
+        f(c);
+    }
+}
+
+/// Runs `f` on the value behind `lock` with local IRQs disabled, as the
+/// console interrupt handler takes the same locks.
+fn lock_irqsave<T, R>(lock: &Mutex<Option<T>>, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+    crate::with_irqs_disabled(|| lock.lock().as_mut().map(f))
+}
+
+/// Returns `true` once the console interrupt is wired up, so the transmit
+/// buffer is drained by the TX-empty interrupt instead of by polling.
+fn irq_driven() -> bool {
+    #[cfg(feature = "irq")]
+    {
+        IRQ.is_completed()
+    }
+    #[cfg(not(feature = "irq"))]
+    {
+        false
+    }
+}
+
+/// Polls the transmit buffer out to the UART.
+///
+/// Only one CPU drains at a time; the others just queue their bytes and
+/// return, leaving them to the CPU that is already draining.
+fn poll_drain() {
+    loop {
+        if DRAINING.swap(true, Ordering::Acquire) {
+            return;
+        }
+        while !lock_irqsave(&TX, Output::drain).unwrap_or(true) {
+            core::hint::spin_loop();
+        }
+        DRAINING.store(false, Ordering::Release);
+        // Bytes queued while we were giving up the drainer role would
+        // otherwise wait for the next write.
+        if lock_irqsave(&TX, |out| out.buf.is_empty()).unwrap_or(true) {
+            return;
+        }
+    }
+}
+
+/// Blocks until every byte written so far has left the UART.
+///
+/// Useful before powering off or from panic paths, where nothing will drain
+/// the transmit buffer later. If the UART type is unknown, it only waits for
+/// the bytes to be handed to the UART FIFO.
+pub fn flush() {
+    while !lock_irqsave(&TX, Output::drain).unwrap_or(true) {
+        core::hint::spin_loop();
+    }
+    if let Some(uart) = UART.get() {
+        while !uart.tx_idle() {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Writes `bytes` to the console without waiting on other CPUs indefinitely.
+///
+/// The console lock is tried for a bounded time; if its holder never lets go
+/// (e.g. it panicked or took an exception while printing), the UART registers
+/// are driven directly, or the bytes are dropped if the UART type is unknown.
+/// It neither allocates nor relies on interrupts, so it can be used from panic
+/// handlers and exception context on any CPU.
+///
+/// Returns `true` if the lock could not be taken and the UART was driven
+/// directly, in which case the output may be interleaved with whatever the
+/// lock holder was printing.
+pub fn emergency_write(bytes: &[u8]) -> bool {
+    crate::with_irqs_disabled(|| emergency_write_irqs_off(bytes))
+}
+
+/// [`emergency_write`] with local IRQs already disabled.
+fn emergency_write_irqs_off(bytes: &[u8]) -> bool {
+    let guard = (0..EMERGENCY_LOCK_TRIES).find_map(|_| {
+        let g = TX.try_lock();
+        if g.is_none() {
+            core::hint::spin_loop();
+        }
+        g
+    });
+    match guard {
+        Some(mut g) => {
+            if let Some(out) = g.as_mut() {
+                for_each_out(bytes, |c| out.write_sync(c));
+            }
+            false
+        }
+        None => match UART.get() {
+            Some(uart) => {
+                for_each_out(bytes, |c| uart.write_polled(c));
+                true
+            }
+            // Nothing to bypass the lock with: the holder may be in the middle
+            // of changing the buffer, so the bytes are dropped.
+            None => false,
+        },
+    }
+}
+
+/// Formatted variant of [`emergency_write`], e.g. for panic messages.
+pub fn emergency_print(args: fmt::Arguments) -> bool {
+    struct Writer(bool);
+
+    impl fmt::Write for Writer {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            self.0 |= emergency_write(s.as_bytes());
+            Ok(())
+        }
+    }
+
+    let mut w = Writer(false);
+    let _ = fmt::write(&mut w, args);
+    w.0
+}
+
+/// Returns the console node: the one at the address given by the `earlycon=`
+/// parameter, as in `earlycon=pl011,0x9000000` or
+/// `earlycon=uart8250,mmio32,0xfe660000`, or else the `/chosen` debug
+/// console.
+///
+/// The boot page table only maps the debug console found by the loader, so
+/// `earlycon=` is honoured only if it names a UART in the same page; any
+/// other UART would fault on the first write.
+pub(crate) fn console_node<'a>(fdt: &Fdt<'a>) -> Option<Node<'a>> {
+    if let Some(addr) = crate::cmdline()
+        .get("earlycon")
+        .and_then(|v| v.split(',').find_map(crate::cmdline::parse_usize))
+        && let Some(debug) = &boot_info().debug_console
+        && debug.base_phys.align_down_4k() == addr.align_down_4k()
+    {
+        let node = fdt.all_nodes().find(|node| {
+            node.reg()
+                .and_then(|mut regs| regs.next())
+                .is_some_and(|reg| reg.address as usize == addr)
+        });
+        if node.is_some() {
+            return node;
+        }
+    }
+    fdt.chosen()?.debugcon()
+}
+
+pub(crate) fn setup_early() -> Option<()> {
+    let ptr = boot_info().fdt?;
+    let fdt = Fdt::from_ptr(ptr).ok()?;
+    let node = console_node(&fdt)?;
+
+    let mut uart = any_uart::Uart::new_by_fdt_node(&node, somehal::mem::phys_to_virt)?;
+    *TX.lock() = uart.tx.take().map(|tx| Output {
+        tx,
+        buf: Deque::new(),
+    });
+    *RX.lock() = uart.rx.take().map(|rx| Input {
+        rx,
+        buf: Deque::new(),
+        throttled: false,
+    });
+    if let Some(raw) = RawUart::new_by_fdt_node(&node) {
+        UART.call_once(|| raw);
+    }
+
+    Some(())
+}
+
+/// Resolves the console interrupt from the [console node](console_node),
+/// unmasks the UART receive interrupt and enables the line.
+///
+/// Input keeps being polled through `read_bytes`, and output drained by the
+/// writers, if the UART type is unknown or the node has no `interrupts`.
+#[cfg(feature = "irq")]
+pub(crate) fn init_irq() -> Option<()> {
+    use alloc::vec::Vec;
+
+    let uart = UART.get()?;
+    let fdt = crate::fdt();
+    let node = console_node(&fdt)?;
+    let cells = node.interrupts()?.next()?.collect::<Vec<_>>();
+    let (irq, _) = crate::fdt::parse_gic_irq(&cells)?;
+
+    uart.set_rx_irq(true);
+    IRQ.call_once(|| irq);
+    // The transmit path depends on this line even if the kernel never
+    // registers a handler for console input.
+    crate::irq::set_enable(irq, true);
+    log::debug!("console IRQ: {irq:#x}");
+    Some(())
+}
+
+/// Moves received bytes into the receive buffer and refills the UART transmit
+/// FIFO if `irq` is the console interrupt.
+///
+/// Returns `true` if the interrupt belongs to the console.
+#[cfg(feature = "irq")]
+pub(crate) fn handle_irq(irq: usize) -> bool {
+    if IRQ.get() != Some(&irq) {
+        return false;
+    }
+    lock_irqsave(&RX, Input::fill);
+    lock_irqsave(&TX, |out| {
+        if out.drain()
+            && let Some(uart) = UART.get()
+        {
+            uart.set_tx_irq(false);
+        }
+    });
+    true
+}
+
+struct ConsoleIfImpl;
+
+#[impl_plat_interface]
+impl ConsoleIf for ConsoleIfImpl {
+    /// Writes given bytes to the console.
+    ///
+    /// The bytes are queued in the transmit buffer, so this only waits for the
+    /// UART when the buffer is full.
+    fn write_bytes(bytes: &[u8]) {
+        let queued = lock_irqsave(&TX, |out| {
+            for_each_out(bytes, |c| out.push(c));
+            if irq_driven()
+                && !out.drain()
+                && let Some(uart) = UART.get()
+            {
+                uart.set_tx_irq(true);
+            }
+        });
+        if queued.is_some() && !irq_driven() {
+            poll_drain();
+        }
+    }
+
+    /// Reads bytes from the console into the given mutable slice.
+    ///
+    /// Returns the number of bytes read.
+    fn read_bytes(bytes: &mut [u8]) -> usize {
+        lock_irqsave(&RX, |input| {
+            input.fill();
+            let mut read_len = 0;
+            while read_len < bytes.len()
+                && let Some(c) = input.pop()
+            {
+                bytes[read_len] = c;
+                read_len += 1;
+            }
+            read_len
+        })
+        .unwrap_or(0)
+    }
+
+    /// Returns the IRQ number for the console input interrupt.
+    ///
+    /// Returns `None` if input interrupt is not supported.
+    #[cfg(feature = "irq")]
+    fn irq_num() -> Option<usize> {
+        IRQ.get().copied()
+    }
+}
@@ -0,0 +1,153 @@
+//! Register-level access to the debug UART.
+//!
+//! `any_uart` only exposes blocking/non-blocking byte I/O, so the interrupt
+//! mask registers of the UARTs we know about are driven here directly.
+
+use fdt_parser::Node;
+use spin::Mutex;
+
+use crate::fdt::{is_compatible, prop_u32};
+
+const PL011_COMPATS: &[&str] = &["arm,pl011", "arm,sbsa-uart"];
+const NS16550_COMPATS: &[&str] = &["ns16550", "ns16550a", "ns8250", "snps,dw-apb-uart"];
+
+mod pl011 {
+    pub const DR: usize = 0x00;
+    pub const FR: usize = 0x18;
+    pub const FR_BUSY: u32 = 1 << 3;
+    pub const FR_TXFF: u32 = 1 << 5;
+    pub const FR_TXFE: u32 = 1 << 7;
+    pub const IMSC: usize = 0x38;
+    pub const IMSC_RXIM: u32 = 1 << 4;
+    pub const IMSC_TXIM: u32 = 1 << 5;
+    pub const IMSC_RTIM: u32 = 1 << 6;
+}
+
+mod ns16550 {
+    pub const THR: usize = 0;
+    pub const IER: usize = 1;
+    pub const IER_ERBFI: u32 = 1 << 0;
+    pub const IER_ETBEI: u32 = 1 << 1;
+    pub const LSR: usize = 5;
+    pub const LSR_THRE: u32 = 1 << 5;
+    pub const LSR_TEMT: u32 = 1 << 6;
+}
+
+/// Serializes read-modify-write of the interrupt mask register, which is
+/// shared by the receive and transmit paths.
+static MASK_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    Pl011,
+    Ns16550 { shift: u32, width: u32 },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RawUart {
+    base: usize,
+    kind: Kind,
+}
+
+impl RawUart {
+    pub fn new_by_fdt_node(node: &Node<'_>) -> Option<Self> {
+        let kind = if is_compatible(node, PL011_COMPATS) {
+            Kind::Pl011
+        } else if is_compatible(node, NS16550_COMPATS) {
+            Kind::Ns16550 {
+                shift: prop_u32(node, "reg-shift").unwrap_or(0),
+                width: prop_u32(node, "reg-io-width").unwrap_or(1),
+            }
+        } else {
+            return None;
+        };
+        let reg = node.reg()?.next()?;
+        let base = somehal::mem::phys_to_virt(reg.address as usize) as usize;
+        Some(Self { base, kind })
+    }
+
+    fn read(&self, reg: usize) -> u32 {
+        unsafe {
+            match self.kind {
+                Kind::Pl011 => ((self.base + reg) as *const u32).read_volatile(),
+                Kind::Ns16550 { shift, width } => {
+                    let addr = self.base + (reg << shift);
+                    if width == 4 {
+                        (addr as *const u32).read_volatile()
+                    } else {
+                        (addr as *const u8).read_volatile() as u32
+                    }
+                }
+            }
+        }
+    }
+
+    fn write(&self, reg: usize, val: u32) {
+        unsafe {
+            match self.kind {
+                Kind::Pl011 => ((self.base + reg) as *mut u32).write_volatile(val),
+                Kind::Ns16550 { shift, width } => {
+                    let addr = self.base + (reg << shift);
+                    if width == 4 {
+                        (addr as *mut u32).write_volatile(val)
+                    } else {
+                        (addr as *mut u8).write_volatile(val as u8)
+                    }
+                }
+            }
+        }
+    }
+
+    fn update(&self, reg: usize, mask: u32, set: bool) {
+        let _g = MASK_LOCK.lock();
+        let old = self.read(reg);
+        self.write(reg, if set { old | mask } else { old & !mask });
+    }
+
+    /// Enables or disables the receive (and receive timeout) interrupt.
+    pub fn set_rx_irq(&self, enable: bool) {
+        match self.kind {
+            Kind::Pl011 => self.update(pl011::IMSC, pl011::IMSC_RXIM | pl011::IMSC_RTIM, enable),
+            Kind::Ns16550 { .. } => self.update(ns16550::IER, ns16550::IER_ERBFI, enable),
+        }
+    }
+
+    /// Enables or disables the transmit FIFO empty interrupt.
+    pub fn set_tx_irq(&self, enable: bool) {
+        match self.kind {
+            Kind::Pl011 => self.update(pl011::IMSC, pl011::IMSC_TXIM, enable),
+            Kind::Ns16550 { .. } => self.update(ns16550::IER, ns16550::IER_ETBEI, enable),
+        }
+    }
+
+    /// Writes `c` by polling the transmit status directly, without going
+    /// through `any_uart`.
+    pub fn write_polled(&self, c: u8) {
+        match self.kind {
+            Kind::Pl011 => {
+                while self.read(pl011::FR) & pl011::FR_TXFF != 0 {
+                    core::hint::spin_loop();
+                }
+                self.write(pl011::DR, c as u32);
+            }
+            Kind::Ns16550 { .. } => {
+                while self.read(ns16550::LSR) & ns16550::LSR_THRE == 0 {
+                    core::hint::spin_loop();
+                }
+                self.write(ns16550::THR, c as u32);
+            }
+        }
+    }
+
+    /// Returns `true` once the UART has shifted out every byte it was given:
+    /// the transmit FIFO is empty and the transmitter is idle.
+    pub fn tx_idle(&self) -> bool {
+        match self.kind {
+            Kind::Pl011 => {
+                let fr = self.read(pl011::FR);
+                fr & pl011::FR_TXFE != 0 && fr & pl011::FR_BUSY == 0
+            }
+            Kind::Ns16550 { .. } => self.read(ns16550::LSR) & ns16550::LSR_TEMT != 0,
+        }
+    }
+}
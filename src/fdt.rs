@@ -1,31 +1,110 @@
-use alloc::vec::Vec;
-use arm_gic_driver::{IntId, fdt_parse_irq_config, v3::Trigger};
+use alloc::{vec, vec::Vec};
+use core::mem::discriminant;
 
-use crate::fdt;
+use arm_gic_driver::{fdt_parse_irq_config, v3::Trigger};
+use fdt_parser::Node;
+use log::warn;
 
-pub fn find_trigger(irq_raw: usize) -> Option<Trigger> {
-    let id = unsafe { IntId::raw(irq_raw as _) };
+use crate::fdt;
 
-    let mut trigger = None;
+/// Builds a table of the trigger mode of every IRQ below `count` that some
+/// device node lists in its `interrupts`, indexed by INTID.
+///
+/// Nodes disagreeing on the trigger of an IRQ are reported; the first one
+/// found wins.
+pub fn irq_triggers(count: usize) -> Vec<Option<Trigger>> {
+    let mut table = vec![None; count];
+    let mut owners: Vec<&str> = vec![""; count];
     let fdt = fdt();
     for node in fdt.all_nodes() {
-        if let Some(irqs) = node.interrupts() {
-            for irq in irqs {
-                let one = irq.collect::<Vec<_>>();
-
-                if one.is_empty() {
-                    continue;
-                }
-
-                if let Ok(c) = fdt_parse_irq_config(&one)
-                    && c.id == id
-                {
-                    trigger = Some(c.trigger);
-                    break;
+        let Some(irqs) = node.interrupts() else {
+            continue;
+        };
+        for irq in irqs {
+            let one = irq.collect::<Vec<_>>();
+            let Some((idx, trigger)) = parse_gic_irq(&one) else {
+                continue;
+            };
+            if idx >= count {
+                continue;
+            }
+            match &table[idx] {
+                None => {
+                    table[idx] = Some(trigger);
+                    owners[idx] = node.name();
                 }
+                Some(t) if discriminant(t) != discriminant(&trigger) => warn!(
+                    "IRQ {idx:#x}: {} wants {trigger:?} but {} wants {t:?}, keeping {t:?}",
+                    node.name(),
+                    owners[idx],
+                ),
+                Some(_) => {}
             }
         }
     }
+    table
+}
+
+/// Decodes a GIC `interrupts` specifier into an INTID and trigger mode.
+///
+/// Besides SPIs (type 0) and PPIs (type 1), the GICv3 binding has extended
+/// SPIs (type 2) and extended PPIs (type 3).
+pub fn parse_gic_irq(cells: &[u32]) -> Option<(usize, Trigger)> {
+    let base = match cells.first()? {
+        2 => 4096,
+        3 => 1056,
+        _ => {
+            let c = fdt_parse_irq_config(cells).ok()?;
+            return Some((c.id.to_u32() as usize, c.trigger));
+        }
+    };
+    let (&num, &flags) = (cells.get(1)?, cells.get(2)?);
+    let trigger = if flags & 0x3 != 0 {
+        Trigger::Edge
+    } else {
+        Trigger::Level
+    };
+    Some((base + num as usize, trigger))
+}
 
-    trigger
+/// Iterates over the entries of a string-list property such as `compatible`.
+pub fn prop_strs<'a>(node: &Node<'a>, name: &str) -> impl Iterator<Item = &'a str> + 'a {
+    node.find_property(name)
+        .map(|p| p.raw_value())
+        .unwrap_or_default()
+        .split(|&b| b == 0)
+        .filter(|s| !s.is_empty())
+        .filter_map(|s| core::str::from_utf8(s).ok())
+}
+
+/// Returns `true` if any entry of the node's `compatible` is in `compats`.
+pub fn is_compatible(node: &Node<'_>, compats: &[&str]) -> bool {
+    prop_strs(node, "compatible").any(|c| compats.contains(&c))
+}
+
+/// Reads a single-cell property.
+pub fn prop_u32(node: &Node<'_>, name: &str) -> Option<u32> {
+    let raw = node.find_property(name)?.raw_value();
+    Some(u32::from_be_bytes(raw.get(..4)?.try_into().ok()?))
+}
+
+/// Reads a property as a list of cells.
+pub fn prop_cells(node: &Node<'_>, name: &str) -> Option<Vec<u32>> {
+    let raw = node.find_property(name)?.raw_value();
+    Some(
+        raw.chunks_exact(4)
+            .map(|c| u32::from_be_bytes(c.try_into().unwrap()))
+            .collect(),
+    )
+}
+
+/// Reads a property holding one or two cells as an integer, as used by
+/// `linux,initrd-start` and friends.
+pub fn prop_uint(node: &Node<'_>, name: &str) -> Option<u64> {
+    let raw = node.find_property(name)?.raw_value();
+    match raw.len() {
+        4 => Some(u32::from_be_bytes(raw.try_into().ok()?) as u64),
+        8 => Some(u64::from_be_bytes(raw.try_into().ok()?)),
+        _ => None,
+    }
 }
@@ -0,0 +1,179 @@
+use alloc::vec::Vec;
+
+use aarch64_cpu::registers::*;
+use axplat::mem::RawRange;
+use fdt_parser::{Node, Status};
+use log::debug;
+use spin::Once;
+
+use crate::fdt::{is_compatible, prop_strs, prop_u32};
+
+/// GICv2 and GICv1 compatibles, as matched by Linux.
+const GIC_V2_COMPATS: &[&str] = &[
+    "arm,gic-400",
+    "arm,cortex-a15-gic",
+    "arm,cortex-a9-gic",
+    "arm,cortex-a7-gic",
+    "arm,cortex-a5-gic",
+    "arm,arm11mp-gic",
+    "arm,arm1176jzf-devchip-gic",
+    "arm,eb11mp-gic",
+    "arm,tc11mp-gic",
+    "arm,pl390",
+    "brcm,brahma-b15-gic",
+    "nvidia,tegra210-agic",
+    "qcom,msm-8660-qgic",
+    "qcom,msm-qgic2",
+];
+const GIC_V3_COMPATS: &[&str] = &["arm,gic-v3"];
+
+static INFO: Once<PlatformInfo> = Once::new();
+
+/// Version and register frames of the GIC, as described by the device tree.
+#[derive(Debug, Clone)]
+pub struct GicInfo {
+    /// 2 or 3.
+    pub version: u32,
+    /// Distributor.
+    pub gicd: RawRange,
+    /// CPU interface (GICv2 only).
+    pub gicc: Option<RawRange>,
+    /// Redistributor regions (GICv3 only).
+    pub gicr: Vec<RawRange>,
+}
+
+/// The console device selected by `/chosen`.
+#[derive(Debug, Clone)]
+pub struct ConsoleInfo {
+    /// Device tree node name.
+    pub name: &'static str,
+    /// First entry of the node's `compatible`.
+    pub compatible: Option<&'static str>,
+    /// Physical base address of the registers.
+    pub base: Option<usize>,
+}
+
+/// Basic facts about the machine, gathered once during `init_later`.
+#[derive(Debug, Clone)]
+pub struct PlatformInfo {
+    /// Root `model` property.
+    pub model: Option<&'static str>,
+    /// Root `compatible` entries.
+    pub compatible: Vec<&'static str>,
+    /// Hardware IDs (MPIDR affinity) of the CPUs that may be brought up,
+    /// indexed by logical CPU ID.
+    pub cpu_ids: Vec<usize>,
+    /// Interrupt controller.
+    pub gic: Option<GicInfo>,
+    /// Frequency of the generic timer in Hz.
+    pub timer_freq: u64,
+    /// Timer interrupt used by the platform.
+    pub timer_irq: Option<usize>,
+    /// Total usable RAM in bytes.
+    pub ram_total: usize,
+    /// Debug console.
+    pub console: Option<ConsoleInfo>,
+    /// Exception level the kernel runs at.
+    pub current_el: u8,
+}
+
+impl PlatformInfo {
+    /// Returns the number of CPUs that may be brought up.
+    pub fn cpu_count(&self) -> usize {
+        self.cpu_ids.len()
+    }
+}
+
+/// Returns `true` if `node` is a GIC supported by this platform.
+pub(crate) fn is_gic(node: &Node<'_>) -> bool {
+    is_compatible(node, GIC_V2_COMPATS) || is_compatible(node, GIC_V3_COMPATS)
+}
+
+/// Finds the GIC node in the device tree.
+pub(crate) fn probe_gic() -> Option<GicInfo> {
+    let fdt = crate::fdt();
+    let node = fdt.all_nodes().find(|node| {
+        !matches!(node.status(), Some(Status::Disabled)) && is_gic(node)
+    })?;
+    let version = if is_compatible(&node, GIC_V3_COMPATS) {
+        3
+    } else {
+        2
+    };
+    let mut regs = node
+        .reg()?
+        .map(|reg| (reg.address as usize, reg.size.unwrap_or_default()));
+    let gicd = regs.next()?;
+    Some(if version == 3 {
+        let count = prop_u32(&node, "#redistributor-regions").unwrap_or(1) as usize;
+        GicInfo {
+            version,
+            gicd,
+            gicc: None,
+            gicr: regs.take(count).collect(),
+        }
+    } else {
+        GicInfo {
+            version,
+            gicd,
+            gicc: regs.next(),
+            gicr: Vec::new(),
+        }
+    })
+}
+
+fn probe_console() -> Option<ConsoleInfo> {
+    let fdt = crate::fdt();
+    let node = crate::console::console_node(&fdt)?;
+    Some(ConsoleInfo {
+        name: node.name(),
+        compatible: prop_strs(&node, "compatible").next(),
+        base: node
+            .reg()
+            .and_then(|mut regs| regs.next())
+            .map(|reg| reg.address as usize),
+    })
+}
+
+pub(crate) fn init() {
+    let info = INFO.call_once(|| {
+        let fdt = crate::fdt();
+        let root = fdt.find_nodes("/").next();
+        PlatformInfo {
+            model: root
+                .as_ref()
+                .and_then(|root| prop_strs(root, "model").next()),
+            compatible: root
+                .as_ref()
+                .map(|root| prop_strs(root, "compatible").collect())
+                .unwrap_or_default(),
+            cpu_ids: cpu_ids(),
+            gic: probe_gic(),
+            timer_freq: CNTFRQ_EL0.get(),
+            timer_irq: crate::time::irq(),
+            ram_total: crate::mem::ram_total(),
+            console: probe_console(),
+            current_el: CurrentEL.read(CurrentEL::EL) as u8,
+        }
+    });
+    debug!("{info:#x?}");
+}
+
+fn cpu_ids() -> Vec<usize> {
+    #[cfg(feature = "smp")]
+    {
+        (0..crate::smp::cpu_count())
+            .map(crate::smp::cpu_idx_to_id)
+            .collect()
+    }
+    #[cfg(not(feature = "smp"))]
+    {
+        alloc::vec![somehal::boot_info().cpu_id]
+    }
+}
+
+/// Returns the platform information snapshot, or `None` before `init_later`
+/// has run on the primary CPU.
+pub fn platform_info() -> Option<&'static PlatformInfo> {
+    INFO.get()
+}
@@ -1,7 +1,7 @@
 use axplat::init::InitIf;
 use log::debug;
 
-use crate::{console, driver};
+use crate::{cmdline, console, driver};
 
 struct InitIfImpl;
 
@@ -31,7 +31,9 @@ impl InitIf for InitIfImpl {
     /// * Exception & interrupt handlers are set up.
     /// * Early console is initialized.
     /// * Current monotonic time and wall time can be obtained.
-    fn init_early(_cpu_id: usize, _arg: usize) {
+    fn init_early(_cpu_id: usize, arg: usize) {
+        // `earlycon=` picks the console.
+        cmdline::setup(arg);
         console::setup_early();
         axcpu::init::init_trap();
         crate::mem::setup();
@@ -71,6 +73,7 @@ impl InitIf for InitIfImpl {
     /// * Other platform devices are initialized.
     fn init_later(_cpu_id: usize, _arg: usize) {
         somehal::mem::flush_tlb(None);
+        crate::mem::log_layout();
         #[cfg(feature = "smp")]
         crate::smp::init();
 
@@ -85,7 +88,9 @@ impl InitIf for InitIfImpl {
             crate::irq::init();
             crate::irq::init_current_cpu();
             crate::time::enable_irqs();
+            console::init_irq();
         }
+        crate::info::init();
     }
 
     /// Initializes the platform at the later stage for secondary cores.
@@ -0,0 +1,172 @@
+use alloc::collections::BTreeMap;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+use log::{debug, warn};
+use spin::Mutex;
+
+use super::{IrqError, gic_version, is_spi, this_cpu_idx};
+
+/// How SPIs without an explicit affinity are routed when they get enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum AffinityPolicy {
+    /// To the CPU that enables them. This is the default.
+    Enabler = 0,
+    /// To one online CPU each, picked round-robin.
+    Spread = 1,
+    /// To any online CPU: 1-of-N routing on GICv3, every online CPU in
+    /// `GICD_ITARGETSR` on GICv2.
+    Distributed = 2,
+}
+
+/// Where an SPI is routed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Route {
+    /// To the CPUs in the mask of logical CPU IDs.
+    Cpus(usize),
+    /// To any online CPU.
+    Any,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Assigned {
+    /// Set through [`set_affinity`].
+    Explicit(usize),
+    /// Picked by [`AffinityPolicy::Spread`].
+    Spread(usize),
+}
+
+static POLICY: AtomicU8 = AtomicU8::new(AffinityPolicy::Enabler as u8);
+/// Logical IDs of the CPUs whose GIC interface is initialized.
+static ONLINE: AtomicUsize = AtomicUsize::new(0);
+static NEXT_CPU: AtomicUsize = AtomicUsize::new(0);
+static ASSIGNED: Mutex<BTreeMap<usize, Assigned>> = Mutex::new(BTreeMap::new());
+
+/// CPUs the masks of logical CPU IDs can hold. CPUs beyond take their
+/// private interrupts but no SPIs, and are never online.
+pub const MAX_AFFINITY_CPUS: usize = usize::BITS as usize;
+
+/// Returns the mask with only `cpu_idx`, empty if it does not fit.
+pub(super) fn cpu_bit(cpu_idx: usize) -> usize {
+    1usize.checked_shl(cpu_idx as u32).unwrap_or(0)
+}
+
+/// Checks once that every CPU fits in the masks.
+pub(super) fn init(cpu_count: usize) {
+    if cpu_count > MAX_AFFINITY_CPUS {
+        warn!(
+            "{cpu_count} CPUs, but interrupts can only be routed to the first \
+             {MAX_AFFINITY_CPUS}"
+        );
+    }
+}
+
+pub(super) fn set_online(cpu_idx: usize) {
+    ONLINE.fetch_or(cpu_bit(cpu_idx), Ordering::SeqCst);
+}
+
+pub(super) fn set_offline(cpu_idx: usize) {
+    ONLINE.fetch_and(!cpu_bit(cpu_idx), Ordering::SeqCst);
+}
+
+/// Returns the mask of logical IDs of the CPUs that can take interrupts.
+pub fn online_cpus() -> usize {
+    ONLINE.load(Ordering::SeqCst)
+}
+
+fn policy() -> AffinityPolicy {
+    match POLICY.load(Ordering::Relaxed) {
+        1 => AffinityPolicy::Spread,
+        2 => AffinityPolicy::Distributed,
+        _ => AffinityPolicy::Enabler,
+    }
+}
+
+/// Sets how SPIs without an explicit affinity are routed from now on.
+///
+/// SPIs that are already enabled keep their routing until they are enabled
+/// again.
+pub fn set_affinity_policy(policy: AffinityPolicy) {
+    POLICY.store(policy as u8, Ordering::Relaxed);
+    ASSIGNED
+        .lock()
+        .retain(|_, a| matches!(a, Assigned::Explicit(_)));
+}
+
+/// Routes the SPI `irq` to the CPUs in `cpu_mask`, a mask of logical CPU IDs.
+///
+/// GICv2 delivers the interrupt to one of the targets. GICv3 can only target
+/// one CPU or all of them, so a mask covering every online CPU selects 1-of-N
+/// routing and any other mask routes to its lowest CPU.
+///
+/// The affinity sticks across later enables of `irq`.
+pub fn set_affinity(irq: usize, cpu_mask: usize) -> Result<(), IrqError> {
+    if !is_spi(irq) {
+        return Err(IrqError::InvalidIrq);
+    }
+    if cpu_mask & online_cpus() == 0 {
+        return Err(IrqError::InvalidCpu);
+    }
+    ASSIGNED.lock().insert(irq, Assigned::Explicit(cpu_mask));
+    apply(irq, route(irq));
+    Ok(())
+}
+
+/// Returns the affinity set through [`set_affinity`], if any.
+pub fn affinity(irq: usize) -> Option<usize> {
+    match ASSIGNED.lock().get(&irq) {
+        Some(&Assigned::Explicit(mask)) => Some(mask),
+        _ => None,
+    }
+}
+
+/// Decides where the SPI `irq` goes when it is enabled on the current CPU.
+pub(super) fn route(irq: usize) -> Route {
+    let online = online_cpus();
+    let mut assigned = ASSIGNED.lock();
+    let mask = match assigned.get(&irq) {
+        Some(&Assigned::Explicit(mask)) => mask & online,
+        Some(&Assigned::Spread(cpu)) => cpu_bit(cpu),
+        None => match policy() {
+            AffinityPolicy::Enabler => cpu_bit(this_cpu_idx()),
+            AffinityPolicy::Distributed => return Route::Any,
+            AffinityPolicy::Spread => {
+                let cpu = next_online(online);
+                assigned.insert(irq, Assigned::Spread(cpu));
+                cpu_bit(cpu)
+            }
+        },
+    };
+    if mask == 0 {
+        // Every CPU it was bound to is gone, or the enabler does not fit.
+        match cpu_bit(this_cpu_idx()) {
+            0 => Route::Any,
+            this => Route::Cpus(this),
+        }
+    } else if mask == online && online.count_ones() > 1 {
+        Route::Any
+    } else {
+        Route::Cpus(mask)
+    }
+}
+
+fn next_online(online: usize) -> usize {
+    if online == 0 {
+        return this_cpu_idx();
+    }
+    loop {
+        let cpu = NEXT_CPU.fetch_add(1, Ordering::Relaxed) % MAX_AFFINITY_CPUS;
+        if online & cpu_bit(cpu) != 0 {
+            return cpu;
+        }
+    }
+}
+
+fn apply(irq: usize, route: Route) {
+    debug!("IRQ({irq:#x}) route: {route:?}");
+    match gic_version() {
+        2 => super::v2::set_route(irq, route),
+        3 => super::v3::set_route(irq, route),
+        _ => panic!("Unsupported GIC version"),
+    }
+}
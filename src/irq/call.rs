@@ -0,0 +1,249 @@
+//! Cross-CPU function calls.
+//!
+//! Each CPU has a lock-free queue of calls. A caller pushes onto the queue of
+//! the target CPU and sends it [`CALL_SGI`], whose handler drains the queue
+//! in order. Waiting callers spin on a counter the calls decrement when done,
+//! and run the calls queued for their own CPU meanwhile, so that two CPUs
+//! calling each other do not deadlock whatever masks the SGI.
+//!
+//! A CPU going offline closes its queue: calls pushed afterwards are refused
+//! and count as done for callers waiting on several CPUs.
+
+use alloc::{boxed::Box, sync::Arc};
+use core::{
+    hint::spin_loop,
+    ptr::{NonNull, null_mut},
+    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
+};
+
+use aarch64_cpu::asm::barrier;
+use axplat::irq::IpiTarget;
+use log::warn;
+use spin::Once;
+
+use super::{
+    IrqError,
+    affinity::{MAX_AFFINITY_CPUS, cpu_bit},
+    cpu_count, online_cpus, register_handler, send_sgi, set_enable, this_cpu_idx,
+};
+use crate::with_irqs_disabled;
+
+/// SGI that tells a CPU to run its queued calls. It is one of the
+/// [platform SGIs](super::PLATFORM_SGIS).
+pub const CALL_SGI: usize = 15;
+
+struct Call {
+    func: Box<dyn FnOnce() + Send>,
+    /// Calls of the same request not done yet, if the caller waits.
+    pending: Option<Arc<AtomicUsize>>,
+    next: *mut Call,
+}
+
+/// Calls queued for one CPU, newest first, or [`closed`].
+struct Queue {
+    head: AtomicPtr<Call>,
+}
+
+/// Head of the queue of an offline CPU. Never a valid call.
+fn closed() -> *mut Call {
+    NonNull::dangling().as_ptr()
+}
+
+/// Queue of each CPU, by logical CPU ID.
+static QUEUES: Once<Box<[Queue]>> = Once::new();
+
+pub(super) fn init() {
+    QUEUES.call_once(|| {
+        (0..cpu_count())
+            .map(|_| Queue {
+                head: AtomicPtr::new(null_mut()),
+            })
+            .collect()
+    });
+    if !register_handler(CALL_SGI, handle) {
+        warn!("SGI {CALL_SGI} is taken, cross-CPU calls will not run");
+    }
+}
+
+/// Enables [`CALL_SGI`], which is banked per CPU, on the current CPU.
+pub(super) fn init_current_cpu() {
+    set_enable(CALL_SGI, true);
+}
+
+/// Queues `call` for `cpu`. Returns `false`, dropping the call, if the CPU
+/// has closed its queue.
+fn push(cpu: usize, call: Box<Call>) -> bool {
+    let queue = &QUEUES.get().unwrap()[cpu];
+    let call = Box::into_raw(call);
+    let mut head = queue.head.load(Ordering::Relaxed);
+    loop {
+        if head == closed() {
+            // SAFETY: `call` was never shared.
+            drop(unsafe { Box::from_raw(call) });
+            return false;
+        }
+        // SAFETY: `call` is not shared until the exchange below succeeds.
+        unsafe { (*call).next = head };
+        match queue
+            .head
+            .compare_exchange_weak(head, call, Ordering::Release, Ordering::Relaxed)
+        {
+            Ok(_) => return true,
+            Err(now) => head = now,
+        }
+    }
+}
+
+/// Handler of [`CALL_SGI`]: runs the calls queued for the current CPU.
+pub(super) fn handle() {
+    let Some(queue) = QUEUES.get().and_then(|q| q.get(this_cpu_idx())) else {
+        return;
+    };
+    let mut head = queue.head.load(Ordering::Acquire);
+    loop {
+        if head.is_null() || head == closed() {
+            return;
+        }
+        match queue.head.compare_exchange_weak(
+            head,
+            null_mut(),
+            Ordering::Acquire,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => break,
+            Err(now) => head = now,
+        }
+    }
+    run_list(head);
+}
+
+/// Closes the queue of the current CPU, going offline, and runs the calls
+/// queued so far.
+pub(super) fn close_current_cpu() {
+    let Some(queue) = QUEUES.get().and_then(|q| q.get(this_cpu_idx())) else {
+        return;
+    };
+    let head = queue.head.swap(closed(), Ordering::Acquire);
+    if head != closed() {
+        run_list(head);
+    }
+}
+
+/// Opens the queue of the current CPU again, coming back online.
+pub(super) fn open_current_cpu() {
+    if let Some(queue) = QUEUES.get().and_then(|q| q.get(this_cpu_idx())) {
+        let _ =
+            queue
+                .head
+                .compare_exchange(closed(), null_mut(), Ordering::Release, Ordering::Relaxed);
+    }
+}
+
+/// Runs a list of calls taken out of a queue.
+fn run_list(mut head: *mut Call) {
+    // Reverse the list to run the calls in order.
+    let mut ordered = null_mut::<Call>();
+    while !head.is_null() {
+        // SAFETY: the list was taken out of the queue, so this CPU owns it.
+        let next = unsafe { (*head).next };
+        unsafe { (*head).next = ordered };
+        ordered = head;
+        head = next;
+    }
+    while !ordered.is_null() {
+        // SAFETY: every node was made by `Box::into_raw` in `push`.
+        let call = unsafe { Box::from_raw(ordered) };
+        ordered = call.next;
+        (call.func)();
+        if let Some(pending) = call.pending {
+            pending.fetch_sub(1, Ordering::Release);
+        }
+    }
+}
+
+/// Sends [`CALL_SGI`] to `cpu`, after the queued call is visible to it.
+fn kick(cpu: usize) {
+    barrier::dsb(barrier::ISHST);
+    send_sgi(CALL_SGI, IpiTarget::Other { cpu_id: cpu });
+}
+
+/// Spins until the calls of a request are done, running the calls queued
+/// for this CPU meanwhile: [`CALL_SGI`] may not get in, with local IRQs
+/// masked, the running priority of a handler, or a raised priority mask.
+fn wait(pending: &AtomicUsize) {
+    while pending.load(Ordering::Acquire) != 0 {
+        with_irqs_disabled(handle);
+        spin_loop();
+    }
+}
+
+/// Runs `f` on the CPU with logical ID `cpu`, in its interrupt context, and if
+/// `wait` returns once it is done. On the current CPU `f` runs right away with
+/// local IRQs disabled. Fails with [`IrqError::InvalidCpu`] if `cpu` is
+/// offline.
+///
+/// The caller must not migrate between CPUs during the call. Waiting is
+/// allowed with local IRQs or priorities masked and in interrupt handlers:
+/// the calls other CPUs queue for this one run while it waits, in the
+/// caller's context with local IRQs disabled, and must not take locks the
+/// caller holds.
+pub fn call_on_cpu(
+    cpu: usize,
+    f: impl FnOnce() + Send + 'static,
+    wait: bool,
+) -> Result<(), IrqError> {
+    if QUEUES.get().is_none() {
+        return Err(IrqError::Unsupported);
+    }
+    if online_cpus() & cpu_bit(cpu) == 0 {
+        return Err(IrqError::InvalidCpu);
+    }
+    if cpu == this_cpu_idx() {
+        with_irqs_disabled(f);
+        return Ok(());
+    }
+    let pending = wait.then(|| Arc::new(AtomicUsize::new(1)));
+    let call = Box::new(Call {
+        func: Box::new(f),
+        pending: pending.clone(),
+        next: null_mut(),
+    });
+    if !push(cpu, call) {
+        return Err(IrqError::InvalidCpu);
+    }
+    kick(cpu);
+    if let Some(pending) = pending {
+        self::wait(&pending);
+    }
+    Ok(())
+}
+
+/// Runs `f` on every online CPU, the current one included, and returns once
+/// all of them are done. The same rules as for [`call_on_cpu`] apply to
+/// waiting.
+pub fn call_on_all(f: impl Fn() + Send + Sync + 'static) -> Result<(), IrqError> {
+    if QUEUES.get().is_none() {
+        return Err(IrqError::Unsupported);
+    }
+    let this = this_cpu_idx();
+    let others = online_cpus() & !cpu_bit(this);
+    let pending = Arc::new(AtomicUsize::new(others.count_ones() as usize));
+    let f = Arc::new(f);
+    for cpu in (0..MAX_AFFINITY_CPUS).filter(|&cpu| others & cpu_bit(cpu) != 0) {
+        let f = f.clone();
+        let call = Box::new(Call {
+            func: Box::new(move || f()),
+            pending: Some(pending.clone()),
+            next: null_mut(),
+        });
+        if push(cpu, call) {
+            kick(cpu);
+        } else {
+            // Gone offline meanwhile: nothing runs there.
+            pending.fetch_sub(1, Ordering::Release);
+        }
+    }
+    with_irqs_disabled(|| f());
+    wait(&pending);
+    Ok(())
+}
@@ -0,0 +1,383 @@
+//! IRQ domains: interrupt controllers cascaded behind the GIC, and the
+//! device tree walks that map a device's interrupts to global IRQ numbers.
+//!
+//! The GIC is the root domain and its INTIDs are used as they are. Every
+//! secondary controller registered with [`register_domain`] gets a block of
+//! [`DOMAIN_SPAN`] global numbers from [`VIRQ_BASE`] on, and its own handler
+//! table. Its parent lines are chained: they get a shared handler that asks
+//! the controller what is pending and runs the handlers of those hwirqs.
+
+use alloc::{vec, vec::Vec};
+
+use fdt_parser::Node;
+use log::{debug, warn};
+use spin::{Mutex, Once};
+
+use super::{IrqError, IrqHandler, register_shared, table::IrqTable, unregister_shared};
+use crate::{
+    fdt::{parse_gic_irq, prop_cells, prop_u32},
+    info::is_gic,
+};
+
+/// First global IRQ number of the secondary domains.
+pub const VIRQ_BASE: usize = 0x1_0000;
+/// Global IRQ numbers reserved for each secondary domain.
+pub const DOMAIN_SPAN: usize = 0x1000;
+const MAX_DOMAINS: usize = 16;
+/// Bound on nested `interrupt-map` translations.
+const MAX_MAP_DEPTH: usize = 16;
+
+/// A secondary interrupt controller, such as a GPIO block used as an
+/// interrupt controller.
+pub trait IrqChip: Send + Sync {
+    /// Translates an interrupt specifier of this controller, as many cells as
+    /// its `#interrupt-cells`, to a hwirq. The default takes the first cell.
+    fn xlate(&self, spec: &[u32]) -> Option<usize> {
+        spec.first().map(|&hwirq| hwirq as usize)
+    }
+
+    /// Unmasks or masks `hwirq`.
+    fn set_enable(&self, hwirq: usize, enabled: bool);
+
+    /// Calls `f` with each pending hwirq, acknowledging it as the controller
+    /// requires. It runs in the handler of a parent line.
+    fn for_each_pending(&self, f: &mut dyn FnMut(usize));
+}
+
+struct Domain {
+    phandle: u32,
+    chip: &'static dyn IrqChip,
+    handlers: IrqTable,
+    size: usize,
+}
+
+static DOMAINS: [Once<Domain>; MAX_DOMAINS] = [const { Once::new() }; MAX_DOMAINS];
+/// Serializes registrations.
+static REGISTER_LOCK: Mutex<()> = Mutex::new(());
+
+/// Registers the controller whose node has `phandle`, serving hwirqs below
+/// `size`, and chains it off the parent lines listed in its node. Returns the
+/// global number of its hwirq 0.
+pub fn register_domain(
+    phandle: u32,
+    size: usize,
+    chip: &'static dyn IrqChip,
+) -> Result<usize, IrqError> {
+    if size > DOMAIN_SPAN {
+        return Err(IrqError::NoSpace);
+    }
+    // Everything that can fail is done before the slot is published, as a
+    // published slot cannot be taken back.
+    let tree = tree();
+    let node = tree.by_phandle(phandle).ok_or(IrqError::InvalidDevice)?;
+    let parents: Vec<usize> = (0..)
+        .map_while(|index| tree.resolve(node, index).ok())
+        .collect();
+    if parents.is_empty() {
+        warn!("IRQ domain {phandle:#x}: no parent interrupt");
+    }
+
+    let _guard = REGISTER_LOCK.lock();
+    if domain_by_phandle(phandle).is_some() {
+        return Err(IrqError::Busy);
+    }
+    let idx = DOMAINS
+        .iter()
+        .position(|d| d.get().is_none())
+        .ok_or(IrqError::NoSpace)?;
+    // Until the slot is published, `handle_chained` finds no domain and
+    // reports the parent interrupt unhandled; the controller has all its
+    // hwirqs masked then anyway.
+    for (i, &parent) in parents.iter().enumerate() {
+        if let Err(e) = register_shared(parent, handle_chained, idx) {
+            for &chained in &parents[..i] {
+                let _ = unregister_shared(chained, handle_chained, idx);
+            }
+            return Err(e);
+        }
+        debug!("IRQ domain {phandle:#x}: chained off IRQ {parent}");
+    }
+    DOMAINS[idx].call_once(|| Domain {
+        phandle,
+        chip,
+        handlers: IrqTable::new([0..size]),
+        size,
+    });
+    let base = VIRQ_BASE + idx * DOMAIN_SPAN;
+    debug!("IRQ domain {phandle:#x}: IRQs {base}..{}", base + size);
+    Ok(base)
+}
+
+fn domain_by_phandle(phandle: u32) -> Option<(usize, &'static Domain)> {
+    DOMAINS
+        .iter()
+        .enumerate()
+        .find_map(|(idx, d)| d.get().filter(|d| d.phandle == phandle).map(|d| (idx, d)))
+}
+
+/// Returns the domain serving the global number `irq` and the hwirq in it.
+fn lookup(irq: usize) -> Option<(&'static Domain, usize)> {
+    let idx = irq.checked_sub(VIRQ_BASE)? / DOMAIN_SPAN;
+    let domain = DOMAINS.get(idx)?.get()?;
+    let hwirq = (irq - VIRQ_BASE) % DOMAIN_SPAN;
+    (hwirq < domain.size).then_some((domain, hwirq))
+}
+
+/// Returns `true` if `irq` belongs to a secondary domain.
+pub(super) fn is_virq(irq: usize) -> bool {
+    irq >= VIRQ_BASE
+}
+
+pub(super) fn set_enable(irq: usize, enabled: bool) {
+    match lookup(irq) {
+        Some((domain, hwirq)) => domain.chip.set_enable(hwirq, enabled),
+        None => warn!("IRQ {irq}: no such domain IRQ"),
+    }
+}
+
+pub(super) fn register_handler(irq: usize, handler: IrqHandler) -> bool {
+    lookup(irq).is_some_and(|(domain, hwirq)| domain.handlers.register_handler(hwirq, handler))
+}
+
+pub(super) fn unregister_handler(irq: usize) -> Option<IrqHandler> {
+    let (domain, hwirq) = lookup(irq)?;
+    domain.handlers.unregister_handler(hwirq)
+}
+
+/// Handler of a parent line of the domain at `idx`.
+fn handle_chained(idx: usize) -> bool {
+    let Some(domain) = DOMAINS[idx].get() else {
+        return false;
+    };
+    let mut pending = false;
+    domain.chip.for_each_pending(&mut |hwirq| {
+        pending = true;
+        if !domain.handlers.handle(hwirq) {
+            warn!("Unhandled IRQ {hwirq} of domain {:#x}", domain.phandle);
+        }
+    });
+    pending
+}
+
+/// Returns the global number of `hwirq` of the controller with `phandle`.
+pub fn irq_mapping(phandle: u32, hwirq: usize) -> Option<usize> {
+    let (idx, domain) = domain_by_phandle(phandle)?;
+    (hwirq < domain.size).then_some(VIRQ_BASE + idx * DOMAIN_SPAN + hwirq)
+}
+
+/// Returns the global number of the `index`-th interrupt of `node`, following
+/// `interrupts-extended`, or `interrupts` and `interrupt-parent`, through any
+/// `interrupt-map` nexus up to the GIC or a registered domain.
+pub fn fdt_irq(node: &Node<'_>, index: usize) -> Result<usize, IrqError> {
+    let tree = tree();
+    let idx = tree.index_of(node).ok_or(IrqError::InvalidDevice)?;
+    tree.resolve(idx, index)
+}
+
+/// Returns the global numbers of every interrupt of `node`.
+pub fn fdt_irqs(node: &Node<'_>) -> Vec<usize> {
+    let tree = tree();
+    let Some(idx) = tree.index_of(node) else {
+        return Vec::new();
+    };
+    (0..)
+        .map_while(|index| tree.resolve(idx, index).ok())
+        .collect()
+}
+
+/// Translates an interrupt through the `interrupt-map` of `nexus`, e.g. a
+/// PCIe INTx pin of a device that is not in the device tree: `unit_addr` is
+/// the child unit address (for PCI, the `phys.hi` cell with the bus, device
+/// and function) and `spec` the child specifier (for PCI, the pin, 1 to 4).
+pub fn map_irq(nexus: &Node<'_>, unit_addr: &[u32], spec: &[u32]) -> Result<usize, IrqError> {
+    let tree = tree();
+    let idx = tree.index_of(nexus).ok_or(IrqError::InvalidDevice)?;
+    tree.translate(idx, unit_addr.to_vec(), spec.to_vec())
+}
+
+/// The device tree nodes with their parents, which the parser does not
+/// provide.
+struct Tree {
+    nodes: Vec<Node<'static>>,
+    parents: Vec<Option<usize>>,
+}
+
+// SAFETY: the nodes only read the device tree blob, which nobody writes.
+unsafe impl Send for Tree {}
+unsafe impl Sync for Tree {}
+
+static TREE: Once<Tree> = Once::new();
+
+/// Returns the device tree walk, done on first use.
+fn tree() -> &'static Tree {
+    TREE.call_once(Tree::new)
+}
+
+impl Tree {
+    fn new() -> Self {
+        let fdt = crate::fdt();
+        let mut nodes: Vec<Node<'static>> = Vec::new();
+        let mut parents = Vec::new();
+        let mut stack: Vec<usize> = Vec::new();
+        for node in fdt.all_nodes() {
+            while let Some(&top) = stack.last()
+                && nodes[top].level >= node.level
+            {
+                stack.pop();
+            }
+            parents.push(stack.last().copied());
+            stack.push(nodes.len());
+            nodes.push(node);
+        }
+        Self { nodes, parents }
+    }
+
+    /// Finds `node`, which may come from another parse of the same blob, by
+    /// the location of its name.
+    fn index_of(&self, node: &Node<'_>) -> Option<usize> {
+        let name = node.name().as_ptr();
+        self.nodes.iter().position(|n| n.name().as_ptr() == name)
+    }
+
+    fn by_phandle(&self, phandle: u32) -> Option<usize> {
+        self.nodes.iter().position(|n| {
+            prop_u32(n, "phandle").or_else(|| prop_u32(n, "linux,phandle")) == Some(phandle)
+        })
+    }
+
+    fn interrupt_cells(&self, idx: usize) -> Option<usize> {
+        prop_u32(&self.nodes[idx], "#interrupt-cells").map(|n| n as usize)
+    }
+
+    /// Returns the interrupt parent of the node at `idx`: the target of the
+    /// nearest `interrupt-parent` or else the tree parent, skipping nodes
+    /// that do not take interrupt specifiers.
+    fn interrupt_parent(&self, mut idx: usize) -> Option<usize> {
+        for _ in 0..self.nodes.len() {
+            idx = match prop_u32(&self.nodes[idx], "interrupt-parent") {
+                Some(phandle) => self.by_phandle(phandle)?,
+                None => self.parents[idx]?,
+            };
+            if self.interrupt_cells(idx).is_some() {
+                return Some(idx);
+            }
+        }
+        None
+    }
+
+    /// Returns the unit address of the node at `idx`: the leading cells of
+    /// its `reg`, as matched by `interrupt-map`.
+    fn unit_addr(&self, idx: usize) -> Vec<u32> {
+        prop_cells(&self.nodes[idx], "reg").unwrap_or_default()
+    }
+
+    fn resolve(&self, idx: usize, index: usize) -> Result<usize, IrqError> {
+        let node = &self.nodes[idx];
+        if let Some(ext) = prop_cells(node, "interrupts-extended") {
+            let mut rest = &ext[..];
+            for i in 0.. {
+                let (&phandle, tail) = rest.split_first().ok_or(IrqError::InvalidIrq)?;
+                let parent = self.by_phandle(phandle).ok_or(IrqError::InvalidIrq)?;
+                let cells = self.interrupt_cells(parent).ok_or(IrqError::InvalidIrq)?;
+                let spec = tail.get(..cells).ok_or(IrqError::InvalidIrq)?;
+                if i == index {
+                    return self.translate(parent, self.unit_addr(idx), spec.to_vec());
+                }
+                rest = &tail[cells..];
+            }
+        }
+        let cells = prop_cells(node, "interrupts").ok_or(IrqError::InvalidIrq)?;
+        let parent = self.interrupt_parent(idx).ok_or(IrqError::InvalidIrq)?;
+        let n = self
+            .interrupt_cells(parent)
+            .filter(|&n| n > 0)
+            .ok_or(IrqError::InvalidIrq)?;
+        let spec = cells
+            .chunks_exact(n)
+            .nth(index)
+            .ok_or(IrqError::InvalidIrq)?;
+        self.translate(parent, self.unit_addr(idx), spec.to_vec())
+    }
+
+    /// Follows `interrupt-map` nexus nodes from `idx` until an interrupt
+    /// controller, and maps the specifier there to a global number.
+    fn translate(
+        &self,
+        mut idx: usize,
+        mut addr: Vec<u32>,
+        mut spec: Vec<u32>,
+    ) -> Result<usize, IrqError> {
+        for _ in 0..MAX_MAP_DEPTH {
+            let node = &self.nodes[idx];
+            if node.find_property("interrupt-controller").is_some() {
+                return self.map_controller(idx, &spec);
+            }
+            let map = prop_cells(node, "interrupt-map").ok_or(IrqError::InvalidIrq)?;
+            (idx, addr, spec) = self.map_nexus(idx, &map, &addr, &spec)?;
+        }
+        warn!("interrupt-map nesting too deep");
+        Err(IrqError::InvalidIrq)
+    }
+
+    /// Looks up the unit address and specifier in the `interrupt-map` of the
+    /// nexus at `idx`, returning the parent with its unit address and
+    /// specifier.
+    fn map_nexus(
+        &self,
+        idx: usize,
+        map: &[u32],
+        addr: &[u32],
+        spec: &[u32],
+    ) -> Result<(usize, Vec<u32>, Vec<u32>), IrqError> {
+        let node = &self.nodes[idx];
+        let addr_cells = prop_u32(node, "#address-cells").unwrap_or(2) as usize;
+        let int_cells = self.interrupt_cells(idx).ok_or(IrqError::InvalidIrq)?;
+        let key_len = addr_cells + int_cells;
+
+        let mut key = vec![0; key_len];
+        for (k, &a) in key.iter_mut().zip(addr.iter().take(addr_cells)) {
+            *k = a;
+        }
+        for (k, &s) in key[addr_cells..].iter_mut().zip(spec) {
+            *k = s;
+        }
+        let mask = prop_cells(node, "interrupt-map-mask").unwrap_or_else(|| vec![!0; key_len]);
+
+        let mut rest = map;
+        while rest.len() > key_len {
+            let parent = self.by_phandle(rest[key_len]).ok_or(IrqError::InvalidIrq)?;
+            let parent_addr_cells =
+                prop_u32(&self.nodes[parent], "#address-cells").unwrap_or(0) as usize;
+            let parent_int_cells = self.interrupt_cells(parent).ok_or(IrqError::InvalidIrq)?;
+            let entry_len = key_len + 1 + parent_addr_cells + parent_int_cells;
+            let entry = rest.get(..entry_len).ok_or(IrqError::InvalidIrq)?;
+            let matches =
+                (0..key_len).all(|i| (key[i] ^ entry[i]) & mask.get(i).copied().unwrap_or(!0) == 0);
+            if matches {
+                let parent_addr = entry[key_len + 1..][..parent_addr_cells].to_vec();
+                let parent_spec = entry[key_len + 1 + parent_addr_cells..].to_vec();
+                return Ok((parent, parent_addr, parent_spec));
+            }
+            rest = &rest[entry_len..];
+        }
+        debug!("{}: no interrupt-map entry for {key:x?}", node.name());
+        Err(IrqError::InvalidIrq)
+    }
+
+    /// Maps a specifier of the interrupt controller at `idx` to a global
+    /// number.
+    fn map_controller(&self, idx: usize, spec: &[u32]) -> Result<usize, IrqError> {
+        let node = &self.nodes[idx];
+        if is_gic(node) {
+            return parse_gic_irq(spec)
+                .map(|(irq, _)| irq)
+                .ok_or(IrqError::InvalidIrq);
+        }
+        let phandle = prop_u32(node, "phandle")
+            .or_else(|| prop_u32(node, "linux,phandle"))
+            .ok_or(IrqError::NotRegistered)?;
+        let (_, domain) = domain_by_phandle(phandle).ok_or(IrqError::NotRegistered)?;
+        let hwirq = domain.chip.xlate(spec).ok_or(IrqError::InvalidIrq)?;
+        irq_mapping(phandle, hwirq).ok_or(IrqError::InvalidIrq)
+    }
+}
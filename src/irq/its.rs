@@ -0,0 +1,527 @@
+//! GICv3 Interrupt Translation Service, turning MSI writes into LPIs.
+//!
+//! Only the first ITS in the device tree is used. A device gets an interrupt
+//! translation table of [`EVENTS_PER_DEVICE`] entries on its first vector and
+//! stays mapped afterwards. Each LPI is delivered to the CPU that allocated
+//! it, through one collection per CPU.
+
+use alloc::{
+    alloc::{Layout, alloc_zeroed},
+    collections::BTreeMap,
+    vec,
+    vec::Vec,
+};
+use core::{arch::asm, hint::spin_loop, ops::Range};
+
+use axplat::mem::virt_to_phys;
+use fdt_parser::Status;
+use log::*;
+use spin::{Mutex, Once};
+
+use super::{
+    DEFAULT_PRIORITY, IrqError, MsiVector,
+    regs::{self, Frame, gicd, gicr},
+    this_cpu_idx,
+};
+use crate::fdt::is_compatible;
+
+/// INTID of the first LPI.
+pub const LPI_BASE: usize = 8192;
+/// Number of LPIs handed out, from [`LPI_BASE`] on.
+const MAX_LPIS: usize = 8192;
+/// INTID bits covering every LPI handed out.
+const LPI_ID_BITS: u32 = 14;
+/// Event ID bits of each device.
+const EVENT_BITS: u32 = 8;
+const EVENTS_PER_DEVICE: usize = 1 << EVENT_BITS;
+/// Device ID bits covered by the device table, if the ITS supports as many.
+const MAX_DEVICE_BITS: u32 = 16;
+/// Collections provided for, one per CPU.
+const MAX_COLLECTIONS: usize = 256;
+
+const CMDQ_SIZE: usize = 0x1_0000;
+/// Alignment of the tables: a 64KB page suits every GITS_BASER page size and
+/// the pending tables.
+const TABLE_ALIGN: usize = 0x1_0000;
+const ITT_ALIGN: usize = 256;
+/// Polls of `GITS_CREADR` before a command is considered lost.
+const CMD_TIMEOUT: usize = 1 << 24;
+
+const ITS_COMPATS: &[&str] = &["arm,gic-v3-its"];
+
+mod gits {
+    pub const CTLR: usize = 0x0000;
+    pub const CTLR_ENABLED: u32 = 1 << 0;
+    pub const CTLR_QUIESCENT: u32 = 1 << 31;
+    pub const TYPER: usize = 0x0008;
+    pub const TYPER_PTA: u64 = 1 << 19;
+    pub const CBASER: usize = 0x0080;
+    pub const CWRITER: usize = 0x0088;
+    pub const CREADR: usize = 0x0090;
+    pub const CREADR_STALLED: u64 = 1 << 0;
+    pub const CREADR_OFFSET: u64 = 0xf_ffe0;
+    pub const BASER: usize = 0x0100;
+    pub const BASER_TYPE_DEVICE: u64 = 1;
+    pub const BASER_TYPE_COLLECTION: u64 = 4;
+    /// In the translation frame, 64KB above the control frame.
+    pub const TRANSLATER: usize = 0x1_0040;
+}
+
+/// Attribute fields of GITS_CBASER, GITS_BASER<n>, GICR_PROPBASER and
+/// GICR_PENDBASER.
+mod attr {
+    pub const VALID: u64 = 1 << 63;
+    pub const PTZ: u64 = 1 << 62;
+    pub const SH_INNER: u64 = 1 << 10;
+    pub const SH_MASK: u64 = 3 << 10;
+    /// Inner cacheability of the ITS registers.
+    pub const ITS_WB: u64 = 7 << 59;
+    pub const ITS_NC: u64 = 1 << 59;
+    pub const ITS_MASK: u64 = 7 << 59;
+    /// Inner cacheability of the redistributor registers.
+    pub const RD_WB: u64 = 7 << 7;
+    pub const RD_NC: u64 = 1 << 7;
+    pub const RD_MASK: u64 = 7 << 7;
+}
+
+/// LPI configuration byte: priority in bits [7:2], bit 1 is RES1.
+const PROP_RES1: u8 = 1 << 1;
+const PROP_ENABLE: u8 = 1 << 0;
+
+static ITS: Once<Mutex<Its>> = Once::new();
+
+struct Its {
+    base: Frame,
+    /// Physical address of `GITS_TRANSLATER`, the MSI doorbell.
+    translater: u64,
+    /// Command queue, by virtual address.
+    cmdq: usize,
+    cmdq_write: usize,
+    /// The command queue is not coherent with the CPU caches.
+    cmdq_flush: bool,
+    pta: bool,
+    itt_entry_size: usize,
+    max_devices: usize,
+    /// LPI configuration table, by virtual and physical address.
+    prop: usize,
+    prop_paddr: u64,
+    /// The configuration table is not coherent with the CPU caches.
+    prop_flush: bool,
+    /// Allocated LPIs, one bit each.
+    used: Vec<u64>,
+    /// Target address of the collection of each CPU, as used by commands.
+    collections: BTreeMap<usize, u64>,
+    /// Allocated events of each mapped device, with their LPI.
+    devices: BTreeMap<u32, BTreeMap<u32, usize>>,
+    /// Device, event and target CPU of each allocated LPI.
+    lpis: BTreeMap<usize, (u32, u32, usize)>,
+}
+
+/// Allocates zeroed memory for a table the GIC reads, returning its virtual and
+/// physical addresses.
+fn alloc_table(size: usize, align: usize) -> Result<(usize, u64), IrqError> {
+    let layout = Layout::from_size_align(size, align).map_err(|_| IrqError::NoSpace)?;
+    let ptr = unsafe { alloc_zeroed(layout) };
+    if ptr.is_null() {
+        return Err(IrqError::NoSpace);
+    }
+    let vaddr = ptr as usize;
+    // Make the zeroes visible even if the GIC does not snoop.
+    clean_dcache(vaddr, size);
+    Ok((vaddr, virt_to_phys(vaddr.into()).as_usize() as u64))
+}
+
+fn clean_dcache(vaddr: usize, size: usize) {
+    let ctr: u64;
+    unsafe { asm!("mrs {0}, ctr_el0", out(reg) ctr) };
+    let line = 4 << ((ctr >> 16) & 0xf);
+    let mut addr = vaddr & !(line - 1);
+    while addr < vaddr + size {
+        unsafe { asm!("dc civac, {0}", in(reg) addr) };
+        addr += line;
+    }
+    unsafe { asm!("dsb sy") };
+}
+
+/// Writes a base register with write-back, inner shareable attributes. If the
+/// GIC does not support shareability there, the register is rewritten as
+/// non-cacheable and `true` is returned: the CPU then has to clean what it
+/// writes to the table.
+fn write_base(frame: Frame, off: usize, val: u64, cache_mask: u64, cache_nc: u64) -> bool {
+    frame.write64(off, val);
+    if frame.read64(off) & attr::SH_MASK != 0 {
+        return false;
+    }
+    frame.write64(off, (val & !(attr::SH_MASK | cache_mask)) | cache_nc);
+    true
+}
+
+fn find_its() -> Option<usize> {
+    let fdt = crate::fdt();
+    let mut nodes = fdt.all_nodes().filter(|node| {
+        !matches!(node.status(), Some(Status::Disabled)) && is_compatible(node, ITS_COMPATS)
+    });
+    let node = nodes.next()?;
+    let base = node.reg()?.next()?.address as usize;
+    if let Some(other) = nodes.next() {
+        warn!("only the first ITS is used, {} ignored", other.name());
+    }
+    Some(base)
+}
+
+/// Sets up the ITS and the LPI configuration table. The redistributors are
+/// set up by [`init_current_cpu`].
+pub fn init() {
+    let typer = regs::gicd().read32(gicd::TYPER);
+    if typer & gicd::TYPER_LPIS == 0 {
+        debug!("GIC does not support LPIs");
+        return;
+    }
+    let id_bits = ((typer >> 19) & 0x1f) + 1;
+    if id_bits < LPI_ID_BITS {
+        warn!("GIC supports only {id_bits} INTID bits, LPIs disabled");
+        return;
+    }
+    let Some(base) = find_its() else {
+        debug!("no ITS found");
+        return;
+    };
+    match Its::new(base) {
+        Ok(its) => {
+            info!("ITS at {base:#x}, doorbell {:#x}", its.translater);
+            ITS.call_once(|| Mutex::new(its));
+        }
+        Err(e) => warn!("ITS at {base:#x} not usable: {e:?}"),
+    }
+}
+
+/// Returns the LPIs that can be handed out, if the ITS is usable.
+pub fn lpi_range() -> Option<Range<usize>> {
+    ITS.get().map(|_| LPI_BASE..LPI_BASE + MAX_LPIS)
+}
+
+/// Enables LPIs on the current CPU's redistributor and maps its collection.
+pub fn init_current_cpu() {
+    let Some(its) = ITS.get() else {
+        return;
+    };
+    if let Err(e) = its.lock().init_current_cpu() {
+        warn!("LPIs not available on CPU {}: {e:?}", this_cpu_idx());
+    }
+}
+
+/// Allocates an LPI for the next free event of `device` and maps it to the
+/// current CPU. The LPI starts disabled.
+pub fn alloc(device: u32) -> Result<MsiVector, IrqError> {
+    ITS.get()
+        .ok_or(IrqError::Unsupported)?
+        .lock()
+        .alloc(device)
+}
+
+/// Unmaps and releases an LPI from [`alloc`].
+pub fn free(lpi: usize) -> Result<(), IrqError> {
+    ITS.get().ok_or(IrqError::Unsupported)?.lock().free(lpi)
+}
+
+pub fn set_enable(lpi: usize, enabled: bool) -> Result<(), IrqError> {
+    ITS.get()
+        .ok_or(IrqError::Unsupported)?
+        .lock()
+        .set_enable(lpi, enabled)
+}
+
+impl Its {
+    fn new(paddr: usize) -> Result<Self, IrqError> {
+        let base = Frame::at(paddr);
+        base.write32(gits::CTLR, base.read32(gits::CTLR) & !gits::CTLR_ENABLED);
+        while base.read32(gits::CTLR) & gits::CTLR_QUIESCENT == 0 {
+            spin_loop();
+        }
+
+        let typer = base.read64(gits::TYPER);
+        let itt_entry_size = ((typer >> 4) & 0xf) as usize + 1;
+        let device_bits = (((typer >> 13) & 0x1f) as u32 + 1).min(MAX_DEVICE_BITS);
+        let mut max_devices = 1 << device_bits;
+        for n in 0..8 {
+            if let Some(covered) = setup_baser(base, n, device_bits)? {
+                max_devices = max_devices.min(covered);
+            }
+        }
+
+        let (cmdq, cmdq_paddr) = alloc_table(CMDQ_SIZE, TABLE_ALIGN)?;
+        let cmdq_flush = write_base(
+            base,
+            gits::CBASER,
+            attr::VALID
+                | attr::ITS_WB
+                | attr::SH_INNER
+                | cmdq_paddr
+                | (CMDQ_SIZE / 0x1000 - 1) as u64,
+            attr::ITS_MASK,
+            attr::ITS_NC,
+        );
+        base.write64(gits::CWRITER, 0);
+
+        let (prop, prop_paddr) = alloc_table(MAX_LPIS, TABLE_ALIGN)?;
+        let disabled = (DEFAULT_PRIORITY & 0xfc) | PROP_RES1;
+        unsafe { core::ptr::write_bytes(prop as *mut u8, disabled, MAX_LPIS) };
+        clean_dcache(prop, MAX_LPIS);
+
+        base.write32(gits::CTLR, base.read32(gits::CTLR) | gits::CTLR_ENABLED);
+        Ok(Self {
+            base,
+            translater: (paddr + gits::TRANSLATER) as u64,
+            cmdq,
+            cmdq_write: 0,
+            cmdq_flush,
+            pta: typer & gits::TYPER_PTA != 0,
+            itt_entry_size,
+            max_devices,
+            prop,
+            prop_paddr,
+            prop_flush: false,
+            used: vec![0; MAX_LPIS / 64],
+            collections: BTreeMap::new(),
+            devices: BTreeMap::new(),
+            lpis: BTreeMap::new(),
+        })
+    }
+
+    fn init_current_cpu(&mut self) -> Result<(), IrqError> {
+        let rd = regs::gicr();
+        if rd.read32(gicr::CTLR) & gicr::CTLR_ENABLE_LPIS != 0 {
+            // The tables cannot be replaced once LPIs are on.
+            error!("LPIs were left enabled by the previous stage");
+            return Err(IrqError::Unsupported);
+        }
+        self.prop_flush |= write_base(
+            rd,
+            gicr::PROPBASER,
+            self.prop_paddr | attr::SH_INNER | attr::RD_WB | (LPI_ID_BITS - 1) as u64,
+            attr::RD_MASK,
+            attr::RD_NC,
+        );
+        let (_, pend) = alloc_table((1 << LPI_ID_BITS) / 8, TABLE_ALIGN)?;
+        write_base(
+            rd,
+            gicr::PENDBASER,
+            pend | attr::PTZ | attr::SH_INNER | attr::RD_WB,
+            attr::RD_MASK,
+            attr::RD_NC,
+        );
+        rd.write32(gicr::CTLR, rd.read32(gicr::CTLR) | gicr::CTLR_ENABLE_LPIS);
+        unsafe { asm!("dsb sy") };
+
+        let rdbase = if self.pta {
+            rd.paddr() as u64 >> 16
+        } else {
+            (rd.read64(gicr::TYPER) >> 8) & 0xffff
+        };
+        let cpu = this_cpu_idx();
+        self.submit(&[cmd::mapc(cpu, rdbase), cmd::sync(rdbase)])?;
+        self.collections.insert(cpu, rdbase);
+        Ok(())
+    }
+
+    /// Queues `cmds` and waits until the ITS has consumed them.
+    fn submit(&mut self, cmds: &[[u64; 4]]) -> Result<(), IrqError> {
+        for cmd in cmds {
+            let next = (self.cmdq_write + 32) % CMDQ_SIZE;
+            while (self.base.read64(gits::CREADR) & gits::CREADR_OFFSET) as usize == next {
+                spin_loop();
+            }
+            let slot = (self.cmdq + self.cmdq_write) as *mut u64;
+            for (i, &dw) in cmd.iter().enumerate() {
+                unsafe { slot.add(i).write_volatile(dw) };
+            }
+            if self.cmdq_flush {
+                clean_dcache(slot as usize, 32);
+            }
+            self.cmdq_write = next;
+        }
+        unsafe { asm!("dsb ishst") };
+        self.base.write64(gits::CWRITER, self.cmdq_write as u64);
+
+        for _ in 0..CMD_TIMEOUT {
+            let readr = self.base.read64(gits::CREADR);
+            if readr & gits::CREADR_STALLED != 0 {
+                error!("ITS command queue stalled at {:#x}", readr & gits::CREADR_OFFSET);
+                return Err(IrqError::Hardware);
+            }
+            if (readr & gits::CREADR_OFFSET) as usize == self.cmdq_write {
+                return Ok(());
+            }
+            spin_loop();
+        }
+        error!("ITS command timeout");
+        Err(IrqError::Hardware)
+    }
+
+    fn alloc_lpi(&mut self) -> Option<usize> {
+        let (word, bits) = self
+            .used
+            .iter_mut()
+            .enumerate()
+            .find(|(_, bits)| **bits != u64::MAX)?;
+        let bit = bits.trailing_ones() as usize;
+        *bits |= 1 << bit;
+        Some(LPI_BASE + word * 64 + bit)
+    }
+
+    fn free_lpi(&mut self, lpi: usize) {
+        let idx = lpi - LPI_BASE;
+        self.used[idx / 64] &= !(1 << (idx % 64));
+    }
+
+    fn alloc(&mut self, device: u32) -> Result<MsiVector, IrqError> {
+        if device as usize >= self.max_devices {
+            return Err(IrqError::InvalidDevice);
+        }
+        let cpu = this_cpu_idx();
+        let rdbase = *self.collections.get(&cpu).ok_or(IrqError::InvalidCpu)?;
+        if !self.devices.contains_key(&device) {
+            let (_, itt) = alloc_table(EVENTS_PER_DEVICE * self.itt_entry_size, ITT_ALIGN)?;
+            self.submit(&[cmd::mapd(device, itt), cmd::sync(rdbase)])?;
+            self.devices.insert(device, BTreeMap::new());
+        }
+        let events = &self.devices[&device];
+        let event = (0..EVENTS_PER_DEVICE as u32)
+            .find(|e| !events.contains_key(e))
+            .ok_or(IrqError::NoSpace)?;
+        let lpi = self.alloc_lpi().ok_or(IrqError::NoSpace)?;
+        if let Err(e) = self.submit(&[cmd::mapti(device, event, lpi, cpu), cmd::sync(rdbase)]) {
+            self.free_lpi(lpi);
+            return Err(e);
+        }
+        self.devices.get_mut(&device).unwrap().insert(event, lpi);
+        self.lpis.insert(lpi, (device, event, cpu));
+        debug!("MSI device {device:#x} event {event} -> LPI {lpi}");
+        Ok(MsiVector {
+            irq: lpi,
+            addr: self.translater,
+            data: event,
+        })
+    }
+
+    /// Returns the device, event and collection target of `lpi`.
+    fn lookup(&self, lpi: usize) -> Result<(u32, u32, u64), IrqError> {
+        let &(device, event, cpu) = self.lpis.get(&lpi).ok_or(IrqError::InvalidIrq)?;
+        Ok((device, event, self.collections[&cpu]))
+    }
+
+    fn free(&mut self, lpi: usize) -> Result<(), IrqError> {
+        let (device, event, rdbase) = self.lookup(lpi)?;
+        self.write_prop(lpi, false);
+        self.submit(&[cmd::discard(device, event), cmd::sync(rdbase)])?;
+        self.lpis.remove(&lpi);
+        if let Some(events) = self.devices.get_mut(&device) {
+            events.remove(&event);
+        }
+        self.free_lpi(lpi);
+        Ok(())
+    }
+
+    fn write_prop(&self, lpi: usize, enabled: bool) {
+        let entry = (self.prop + lpi - LPI_BASE) as *mut u8;
+        let mut val = (DEFAULT_PRIORITY & 0xfc) | PROP_RES1;
+        if enabled {
+            val |= PROP_ENABLE;
+        }
+        unsafe { entry.write_volatile(val) };
+        if self.prop_flush {
+            clean_dcache(entry as usize, 1);
+        } else {
+            unsafe { asm!("dsb ishst") };
+        }
+    }
+
+    fn set_enable(&mut self, lpi: usize, enabled: bool) -> Result<(), IrqError> {
+        let (device, event, rdbase) = self.lookup(lpi)?;
+        self.write_prop(lpi, enabled);
+        self.submit(&[cmd::inv(device, event), cmd::sync(rdbase)])
+    }
+}
+
+/// Allocates the table described by `GITS_BASER<n>`, if it is one we use.
+/// Returns the number of device IDs covered for the device table.
+fn setup_baser(base: Frame, n: usize, device_bits: u32) -> Result<Option<usize>, IrqError> {
+    const PAGE_SIZE_SHIFT: u32 = 8;
+    const PAGE_SIZE_MASK: u64 = 3 << PAGE_SIZE_SHIFT;
+
+    let off = gits::BASER + n * 8;
+    let val = base.read64(off);
+    let ty = (val >> 56) & 7;
+    let entry_size = ((val >> 48) & 0x1f) as usize + 1;
+    let entries = match ty {
+        gits::BASER_TYPE_DEVICE => 1 << device_bits,
+        gits::BASER_TYPE_COLLECTION => MAX_COLLECTIONS,
+        _ => return Ok(None),
+    };
+
+    // Take the largest page size the ITS accepts.
+    let (page_size, page_field) = [(0x1_0000, 2), (0x4000, 1), (0x1000, 0)]
+        .into_iter()
+        .find(|&(_, field)| {
+            base.write64(off, (val & !PAGE_SIZE_MASK) | field << PAGE_SIZE_SHIFT);
+            base.read64(off) & PAGE_SIZE_MASK == field << PAGE_SIZE_SHIFT
+        })
+        .unwrap_or((0x1000, 0));
+    let pages = (entries * entry_size).div_ceil(page_size).min(256);
+    let (_, paddr) = alloc_table(pages * page_size, TABLE_ALIGN)?;
+    write_base(
+        base,
+        off,
+        attr::VALID
+            | attr::ITS_WB
+            | ty << 56
+            | (entry_size as u64 - 1) << 48
+            | paddr
+            | attr::SH_INNER
+            | page_field << PAGE_SIZE_SHIFT
+            | (pages - 1) as u64,
+        attr::ITS_MASK,
+        attr::ITS_NC,
+    );
+    debug!("GITS_BASER{n}: type {ty}, {pages} pages of {page_size:#x} at {paddr:#x}");
+    Ok((ty == gits::BASER_TYPE_DEVICE).then_some(pages * page_size / entry_size))
+}
+
+/// ITS command encodings.
+mod cmd {
+    use super::{EVENT_BITS, attr};
+
+    pub fn mapd(device: u32, itt: u64) -> [u64; 4] {
+        [
+            0x08 | (device as u64) << 32,
+            (EVENT_BITS - 1) as u64,
+            attr::VALID | itt,
+            0,
+        ]
+    }
+
+    pub fn mapc(icid: usize, rdbase: u64) -> [u64; 4] {
+        [0x09, 0, attr::VALID | rdbase << 16 | icid as u64, 0]
+    }
+
+    pub fn mapti(device: u32, event: u32, intid: usize, icid: usize) -> [u64; 4] {
+        [
+            0x0a | (device as u64) << 32,
+            event as u64 | (intid as u64) << 32,
+            icid as u64,
+            0,
+        ]
+    }
+
+    pub fn inv(device: u32, event: u32) -> [u64; 4] {
+        [0x0c | (device as u64) << 32, event as u64, 0, 0]
+    }
+
+    pub fn discard(device: u32, event: u32) -> [u64; 4] {
+        [0x0f | (device as u64) << 32, event as u64, 0, 0]
+    }
+
+    pub fn sync(rdbase: u64) -> [u64; 4] {
+        [0x05, 0, rdbase << 16, 0]
+    }
+}
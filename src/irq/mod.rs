@@ -1,23 +1,105 @@
-use core::sync::atomic::AtomicI32;
+use alloc::{vec, vec::Vec};
+use core::{ops::Range, sync::atomic::AtomicI32};
 
 use aarch64_cpu::registers::*;
-use axplat::irq::{HandlerTable, IrqHandler, IrqIf};
+use axplat::irq::{IrqHandler, IrqIf};
 use log::*;
 use rdif_intc::*;
 use rdrive::Device;
-use spin::Mutex;
+use spin::{Mutex, Once};
 
-use crate::fdt::find_trigger;
+use self::{
+    regs::{Frame, gicd, gicr},
+    table::IrqTable,
+};
+use crate::fdt::{irq_triggers, parse_gic_irq};
 
+mod affinity;
+mod call;
+mod domain;
+mod its;
+mod msi;
+#[cfg(feature = "pseudo-nmi")]
+mod nmi;
+mod pm;
+mod prio;
+mod private;
+mod regs;
+mod sgi;
+mod shared;
+mod stats;
+mod storm;
+mod table;
 mod v2;
+mod v2m;
 mod v3;
 
-/// The maximum number of IRQs.
-const MAX_IRQ_COUNT: usize = 1024;
+pub use affinity::{
+    AffinityPolicy, MAX_AFFINITY_CPUS, affinity, online_cpus, set_affinity, set_affinity_policy,
+};
+pub use call::{CALL_SGI, call_on_all, call_on_cpu};
+pub use domain::{
+    DOMAIN_SPAN, IrqChip, VIRQ_BASE, fdt_irq, fdt_irqs, irq_mapping, map_irq, register_domain,
+};
+pub use its::LPI_BASE;
+pub use msi::{MsiVector, alloc_msi, free_msi};
+#[cfg(feature = "pseudo-nmi")]
+pub use nmi::{
+    NMI_PRIORITY, PMR_IRQ_OFF, is_nmi, local_irq_disable, local_irq_enable, local_irq_restore,
+    local_irq_save, set_nmi,
+};
+pub use pm::{
+    restore_distributor, resume_current_cpu, save_distributor, suspend_current_cpu,
+};
+pub use prio::{
+    DEFAULT_PRIORITY, nested, priority, priority_mask, set_nested, set_priority,
+    set_priority_mask,
+};
+pub use private::{CpuTarget, register_private, unregister_private};
+pub use sgi::{
+    PLATFORM_SGIS, RESCHED_SGI, SGI_COUNT, alloc_sgi, free_sgi, reserve_sgi, sgi_owner, sgi_owners,
+};
+pub use shared::{SharedHandler, register_shared, unregister_shared};
+pub use stats::{IrqCount, IrqStats, irq_stats};
+pub use storm::{
+    StormConfig, is_storming, rearm_irq, report_storms, set_storm_config, storm_config,
+    storming_irqs,
+};
+
+/// INTID of the first extended PPI (GICv3.1).
+const EPPI_BASE: usize = 1056;
+/// INTID of the first extended SPI (GICv3.1).
+const ESPI_BASE: usize = 4096;
 
 static VERSION: AtomicI32 = AtomicI32::new(0);
 
-static IRQ_HANDLER_TABLE: HandlerTable<MAX_IRQ_COUNT> = HandlerTable::new();
+/// Handlers of every INTID the GIC implements, built in [`init`].
+static IRQ_HANDLER_TABLE: Once<IrqTable> = Once::new();
+
+/// Trigger mode of each IRQ as given by the device tree, indexed by INTID.
+static TRIGGERS: Once<Vec<Option<arm_gic_driver::v3::Trigger>>> = Once::new();
+
+/// Errors of the interrupt configuration APIs of this platform.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// The IRQ number is out of range or of the wrong kind for the request.
+    InvalidIrq,
+    /// No online CPU matches the request.
+    InvalidCpu,
+    /// The device ID is out of the range the interrupt controller handles.
+    InvalidDevice,
+    /// Every interrupt or table entry that could serve the request is taken,
+    /// or the tables could not be allocated.
+    NoSpace,
+    /// The interrupt controller lacks the feature.
+    Unsupported,
+    /// The interrupt controller did not complete a command.
+    Hardware,
+    /// The IRQ already has a handler that excludes the request.
+    Busy,
+    /// The handler to remove is not registered.
+    NotRegistered,
+}
 
 struct IrqIfImpl;
 
@@ -31,10 +113,11 @@ impl IrqIf for IrqIfImpl {
     /// Registers an IRQ handler for the given IRQ.
     ///
     /// It also enables the IRQ if the registration succeeds. It returns `false`
-    /// if the registration failed.
+    /// if the registration failed, including when the IRQ has
+    /// [shared handlers](register_shared).
     fn register(irq_num: usize, handler: IrqHandler) -> bool {
         trace!("register handler IRQ {}", irq_num);
-        if IRQ_HANDLER_TABLE.register_handler(irq_num, handler) {
+        if shared::register_exclusive(irq_num, || register_handler(irq_num, handler)) {
             Self::set_enable(irq_num, true);
             return true;
         }
@@ -48,8 +131,11 @@ impl IrqIf for IrqIfImpl {
     /// existing handler if it is registered, `None` otherwise.
     fn unregister(irq_num: usize) -> Option<IrqHandler> {
         trace!("unregister handler IRQ {}", irq_num);
+        if shared::is_shared(irq_num) {
+            return None;
+        }
         Self::set_enable(irq_num, false);
-        IRQ_HANDLER_TABLE.unregister_handler(irq_num)
+        unregister_handler(irq_num)
     }
 
     /// Handles the IRQ.
@@ -66,20 +152,67 @@ impl IrqIf for IrqIfImpl {
     }
 
     fn send_ipi(id: usize, target: axplat::irq::IpiTarget) {
-        match gic_version() {
-            2 => v2::send_ipi(id, target),
-            3 => v3::send_ipi(id, target),
-            _ => panic!("Unsupported GIC version"),
-        }
+        send_sgi(id, target);
     }
 }
 
 pub(crate) fn init() {
+    regs::init();
+    affinity::init(cpu_count());
+    let mut ranges = intid_ranges();
+    let count = ranges.iter().map(|r| r.end).max().unwrap_or_default();
+    TRIGGERS.call_once(|| irq_triggers(count));
+
     let intc = get_gicd();
     debug!("Initializing GICD...");
     let mut gic = intc.lock().unwrap();
     gic.open().unwrap();
     debug!("GICD initialized");
+    drop(gic);
+
+    match regs::info().version {
+        2 => v2m::init(),
+        3 => its::init(),
+        _ => {}
+    }
+    if let Some(espi) = ranges.iter().find(|r| r.start == ESPI_BASE) {
+        v3::init_espi(espi.len());
+    }
+    ranges.extend(its::lpi_range());
+    debug!("IRQ ranges: {ranges:x?}");
+    IRQ_HANDLER_TABLE.call_once(|| IrqTable::new(ranges));
+    stats::init();
+    storm::init();
+    call::init();
+}
+
+/// Returns the ranges of INTIDs, other than LPIs, the GIC implements, from
+/// `GICD_TYPER` and, for the extended PPIs, `GICR_TYPER`.
+fn intid_ranges() -> Vec<Range<usize>> {
+    let typer = regs::gicd().read32(gicd::TYPER);
+    let lines = 32 * ((typer & 0x1f) as usize + 1);
+    let mut ranges = vec![0..lines.min(1020)];
+    if regs::info().version != 3 {
+        return ranges;
+    }
+    // Every redistributor implements the same PPIs.
+    if let Some(&(base, _)) = regs::info().gicr.first() {
+        let ppi_num = (Frame::at(base).read64(gicr::TYPER) >> 27) & 0x1f;
+        if ppi_num > 0 {
+            ranges.push(EPPI_BASE..EPPI_BASE + 32 * ppi_num as usize);
+        }
+    }
+    if typer & gicd::TYPER_ESPI != 0 {
+        let espi_range = ((typer >> 27) & 0x1f) as usize;
+        ranges.push(ESPI_BASE..ESPI_BASE + 32 * (espi_range + 1));
+    }
+    ranges
+}
+
+/// Returns the number of extended PPIs of the current CPU's redistributor.
+/// GICv3 only.
+fn eppi_count() -> usize {
+    32 * ((regs::gicr().read64(gicr::TYPER) >> 27) & 0x1f) as usize
 }
 
 fn gic_version() -> i32 {
@@ -113,6 +246,11 @@ pub(crate) fn init_current_cpu() {
         3 => v3::init_current_cpu(),
         _ => panic!("Unsupported GIC version"),
     }
+    prio::init_current_cpu();
+    #[cfg(feature = "pseudo-nmi")]
+    nmi::init_current_cpu();
+    call::init_current_cpu();
+    private::init_current_cpu();
     debug!("GIC initialized for current CPU");
 }
 
@@ -124,8 +262,90 @@ fn current_cpu() -> usize {
     MPIDR_EL1.get() as usize & 0xffffff
 }
 
+/// Returns the logical ID of the current CPU.
+fn this_cpu_idx() -> usize {
+    #[cfg(feature = "smp")]
+    {
+        crate::smp::cpu_id_to_idx(current_cpu())
+    }
+    #[cfg(not(feature = "smp"))]
+    {
+        0
+    }
+}
+
+/// Returns the number of CPUs that may be brought up.
+fn cpu_count() -> usize {
+    #[cfg(feature = "smp")]
+    {
+        crate::smp::cpu_count()
+    }
+    #[cfg(not(feature = "smp"))]
+    {
+        1
+    }
+}
+
+/// Returns the hardware ID (MPIDR affinity) of the CPU with logical ID
+/// `cpu_idx`.
+fn cpu_hw_id(cpu_idx: usize) -> usize {
+    #[cfg(feature = "smp")]
+    {
+        crate::smp::cpu_idx_to_id(cpu_idx)
+    }
+    #[cfg(not(feature = "smp"))]
+    {
+        let _ = cpu_idx;
+        current_cpu()
+    }
+}
+
+/// Returns `true` if `irq` is a shared peripheral interrupt, extended SPIs
+/// included.
+fn is_spi(irq: usize) -> bool {
+    (32..1020).contains(&irq) || is_espi(irq)
+}
+
+fn is_espi(irq: usize) -> bool {
+    (ESPI_BASE..ESPI_BASE + 1024).contains(&irq) && gic_version() == 3
+}
+
+fn is_eppi(irq: usize) -> bool {
+    (EPPI_BASE..EPPI_BASE + 64).contains(&irq) && gic_version() == 3
+}
+
+/// Returns `true` if `irq` is banked per CPU: an SGI, a PPI or an extended
+/// PPI.
+fn is_private(irq: usize) -> bool {
+    irq < 32 || is_eppi(irq)
+}
+
+fn send_sgi(id: usize, target: axplat::irq::IpiTarget) {
+    match gic_version() {
+        2 => v2::send_ipi(id, target),
+        3 => v3::send_ipi(id, target),
+        _ => panic!("Unsupported GIC version"),
+    }
+}
+
+fn trigger(irq_raw: usize) -> Option<arm_gic_driver::v3::Trigger> {
+    *TRIGGERS.get()?.get(irq_raw)?
+}
+
 pub(crate) fn set_enable(irq_raw: usize, enabled: bool) {
-    let t = find_trigger(irq_raw);
+    if domain::is_virq(irq_raw) {
+        domain::set_enable(irq_raw, enabled);
+        return;
+    }
+    if irq_raw >= LPI_BASE {
+        if let Err(e) = its::set_enable(irq_raw, enabled) {
+            warn!("LPI {irq_raw} set enable {enabled} failed: {e:?}");
+        }
+        return;
+    }
+    let t = trigger(irq_raw).or_else(|| {
+        v2m::is_msi(irq_raw).then_some(arm_gic_driver::v3::Trigger::Edge)
+    });
     trace!(
         "set_enable: irq_raw={:#x}, trigger={:?}, enabled={}",
         irq_raw, t, enabled
@@ -137,14 +357,78 @@ pub(crate) fn set_enable(irq_raw: usize, enabled: bool) {
     }
 }
 
+/// Runs the platform's own handler for `irq_num`, if any, and then the one
+/// registered through [`IrqIf::register`], the current CPU's one for private
+/// IRQs, or the [shared handlers](register_shared).
+///
+/// With [nested handling](set_nested) the handlers run with interrupts
+/// unmasked, leaving preemption to the GIC's running priority. In pseudo-NMI
+/// mode they otherwise run with only pseudo-NMIs unmasked.
+///
+/// Returns `false` if nobody handled the IRQ.
+fn dispatch(irq_num: usize) -> bool {
+    let nested = prio::nested();
+    if nested {
+        axcpu::asm::enable_irqs();
+    }
+    #[cfg(feature = "pseudo-nmi")]
+    let nmi_window = (!nested && nmi::active()).then(nmi::open_window);
+    let console = crate::console::handle_irq(irq_num);
+    let handled = if is_private(irq_num) {
+        private::handle(irq_num)
+    } else {
+        IRQ_HANDLER_TABLE
+            .get()
+            .is_some_and(|table| table.handle(irq_num))
+    } || shared::handle(irq_num)
+        || console;
+    stats::record(irq_num, handled);
+    storm::check(irq_num, handled);
+    #[cfg(feature = "pseudo-nmi")]
+    if let Some(pmr) = nmi_window {
+        nmi::close_window(pmr);
+    }
+    if nested {
+        axcpu::asm::disable_irqs();
+    }
+    handled
+}
+
+/// Registers `handler` for `irq_num`. Fails before [`init`], for INTIDs the
+/// GIC does not implement and for INTIDs that already have a handler.
+///
+/// IRQs of [secondary domains](register_domain) go to their domain's table,
+/// and private IRQs to the [table of every CPU](register_private).
+fn register_handler(irq_num: usize, handler: IrqHandler) -> bool {
+    if domain::is_virq(irq_num) {
+        return domain::register_handler(irq_num, handler);
+    }
+    if is_private(irq_num) {
+        return register_private(irq_num, handler, CpuTarget::All).is_ok();
+    }
+    IRQ_HANDLER_TABLE
+        .get()
+        .is_some_and(|table| table.register_handler(irq_num, handler))
+}
+
+fn unregister_handler(irq_num: usize) -> Option<IrqHandler> {
+    if domain::is_virq(irq_num) {
+        return domain::unregister_handler(irq_num);
+    }
+    if is_private(irq_num) {
+        return unregister_private(irq_num, CpuTarget::All).ok();
+    }
+    IRQ_HANDLER_TABLE.get()?.unregister_handler(irq_num)
+}
+
 pub fn parse_fdt_irqs(fdt_irqs: &[u32]) -> IrqConfig {
-    let raw = arm_gic_driver::fdt_parse_irq_config(fdt_irqs).unwrap();
+    let (irq, trigger) = parse_gic_irq(fdt_irqs).unwrap();
     IrqConfig {
-        irq: (raw.id.to_u32() as usize).into(),
-        trigger: match raw.trigger {
+        irq: irq.into(),
+        trigger: match trigger {
             arm_gic_driver::v3::Trigger::Edge => Trigger::EdgeRising,
             arm_gic_driver::v3::Trigger::Level => Trigger::LevelHigh,
         },
-        is_private: raw.id.is_private(),
+        is_private: is_private(irq),
     }
 }
@@ -0,0 +1,64 @@
+//! Message-signalled interrupts.
+
+use axplat::irq::IrqHandler;
+use log::warn;
+
+use super::{IrqError, gic_version, its, register_handler, set_enable, unregister_handler, v2m};
+
+/// A message-signalled interrupt: a device raises `irq` by writing `data`, as
+/// a 32-bit value, to the physical address `addr`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsiVector {
+    /// INTID the message is delivered as.
+    pub irq: usize,
+    /// Doorbell address.
+    pub addr: u64,
+    /// Message payload.
+    pub data: u32,
+}
+
+/// Allocates an MSI for the device `device_id`, registers `handler` for it and
+/// enables it.
+///
+/// With a GICv3 ITS the vector is an LPI and `device_id` is the ID the ITS
+/// sees for the writer, e.g. the PCI requester ID after any `msi-map`
+/// translation. It is routed to the current CPU.
+///
+/// With GICv2m the vector is an edge-triggered SPI and `device_id` is unused.
+/// It is routed like any other SPI.
+pub fn alloc_msi(device_id: u32, handler: IrqHandler) -> Result<MsiVector, IrqError> {
+    let vector = match gic_version() {
+        2 => v2m::alloc()?,
+        3 => its::alloc(device_id)?,
+        _ => return Err(IrqError::Unsupported),
+    };
+    if !register_handler(vector.irq, handler) {
+        warn!("register handler for MSI {} failed", vector.irq);
+        let _ = release(vector.irq);
+        return Err(IrqError::InvalidIrq);
+    }
+    set_enable(vector.irq, true);
+    Ok(vector)
+}
+
+/// Disables an MSI from [`alloc_msi`], unregisters its handler and releases
+/// it.
+pub fn free_msi(vector: &MsiVector) -> Result<(), IrqError> {
+    if gic_version() == 2 {
+        if !v2m::is_msi(vector.irq) {
+            return Err(IrqError::InvalidIrq);
+        }
+        set_enable(vector.irq, false);
+    }
+    release(vector.irq)?;
+    unregister_handler(vector.irq);
+    Ok(())
+}
+
+fn release(irq: usize) -> Result<(), IrqError> {
+    match gic_version() {
+        2 => v2m::free(irq),
+        3 => its::free(irq),
+        _ => Err(IrqError::Unsupported),
+    }
+}
@@ -0,0 +1,134 @@
+//! Pseudo-NMIs on GICv3.
+//!
+//! The CPU has no maskable-by-priority interrupt line, so the local "interrupt
+//! disable" of this module lowers `ICC_PMR_EL1` to [`PMR_IRQ_OFF`] instead of
+//! setting `PSTATE.I`. Interrupts set up with [`set_nmi`] are more urgent
+//! than that mask and keep firing inside such critical sections, and inside
+//! ordinary handlers too, which run with the same mask.
+//!
+//! Only critical sections using [`local_irq_disable`] or [`local_irq_save`]
+//! let pseudo-NMIs in. Code that masks interrupts through `PSTATE.I`, which
+//! includes the kernel's own critical sections (`axcpu`, `kernel_guard`) and
+//! the platform's locks, masks pseudo-NMIs as well; a watchdog can only
+//! preempt the former, and interrupt handlers of this platform.
+//!
+//! On GICv2 the functions here fall back to `PSTATE.I` and [`set_nmi`] fails.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use axcpu::asm::{disable_irqs, enable_irqs, irqs_enabled};
+
+use super::{DEFAULT_PRIORITY, IrqError, gic_version, prio, regs};
+
+/// Priority of pseudo-NMIs.
+pub const NMI_PRIORITY: u8 = 0x40;
+/// Priority mask of a CPU with interrupts locally disabled: only pseudo-NMIs
+/// get through.
+pub const PMR_IRQ_OFF: u8 = 0x80;
+
+/// Set once the GIC interface of the boot CPU runs in pseudo-NMI mode. Every
+/// CPU has the same GIC version, so this holds for the others as they come
+/// up.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Priority mask of the current CPU with interrupts locally enabled.
+#[percpu::def_percpu]
+static PMR_IRQ_ON: u8 = 0xff;
+
+/// Returns `true` if local interrupt masking goes through the priority mask.
+pub fn active() -> bool {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+pub(super) fn init_current_cpu() {
+    if gic_version() != 3 {
+        return;
+    }
+    PMR_IRQ_ON.write_current(regs::read_icc_pmr());
+    ACTIVE.store(true, Ordering::Relaxed);
+}
+
+/// Records `mask` as the current CPU's mask with interrupts enabled, applying
+/// it unless they are locally disabled. Returns `false` if not in pseudo-NMI
+/// mode.
+pub(super) fn set_irq_on_mask(mask: u8) -> bool {
+    if !active() {
+        return false;
+    }
+    PMR_IRQ_ON.write_current(mask);
+    if regs::read_icc_pmr() != PMR_IRQ_OFF {
+        regs::write_icc_pmr(mask);
+    }
+    true
+}
+
+/// Disables interrupts, but not pseudo-NMIs, on the current CPU.
+pub fn local_irq_disable() {
+    if active() {
+        regs::write_icc_pmr(PMR_IRQ_OFF);
+    } else {
+        disable_irqs();
+    }
+}
+
+/// Enables interrupts on the current CPU, clearing `PSTATE.I` as well.
+pub fn local_irq_enable() {
+    if active() {
+        regs::write_icc_pmr(PMR_IRQ_ON.read_current());
+    }
+    enable_irqs();
+}
+
+/// Disables interrupts, but not pseudo-NMIs, on the current CPU and returns
+/// the previous state for [`local_irq_restore`].
+pub fn local_irq_save() -> usize {
+    let flags = if active() {
+        regs::read_icc_pmr() as usize
+    } else {
+        irqs_enabled() as usize
+    };
+    local_irq_disable();
+    flags
+}
+
+/// Restores the state saved by [`local_irq_save`]. `PSTATE.I` is left alone
+/// in pseudo-NMI mode, as [`local_irq_save`] does not touch it.
+pub fn local_irq_restore(flags: usize) {
+    if active() {
+        regs::write_icc_pmr(flags as u8);
+    } else if flags != 0 {
+        enable_irqs();
+    }
+}
+
+/// Makes `irq` a pseudo-NMI, or a regular interrupt again.
+///
+/// The handler of a pseudo-NMI can run in the middle of any critical section
+/// using [`local_irq_disable`], so it must not take locks such code may hold.
+/// SGIs and PPIs are only configured on the current CPU.
+pub fn set_nmi(irq: usize, enable: bool) -> Result<(), IrqError> {
+    if !active() {
+        return Err(IrqError::Unsupported);
+    }
+    prio::set_priority(irq, if enable { NMI_PRIORITY } else { DEFAULT_PRIORITY })
+}
+
+/// Returns `true` if `irq` is a pseudo-NMI on the current CPU.
+pub fn is_nmi(irq: usize) -> bool {
+    active() && prio::priority(irq).is_ok_and(|p| p < PMR_IRQ_OFF)
+}
+
+/// Opens a window for pseudo-NMIs while an ordinary interrupt is handled:
+/// masks every other interrupt through the priority mask and clears
+/// `PSTATE.I`. Returns the mask to give to [`close_window`].
+pub(super) fn open_window() -> u8 {
+    let pmr = regs::read_icc_pmr();
+    regs::write_icc_pmr(PMR_IRQ_OFF);
+    enable_irqs();
+    pmr
+}
+
+pub(super) fn close_window(pmr: u8) {
+    disable_irqs();
+    regs::write_icc_pmr(pmr);
+}
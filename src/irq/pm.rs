@@ -0,0 +1,352 @@
+//! Saving and restoring the GIC state around CPU suspend and power-down.
+//!
+//! A CPU going down calls [`suspend_current_cpu`] and, once powered up again,
+//! [`resume_current_cpu`]; its SGI, PPI and extended PPI configuration and its
+//! priority mask come back as they were, and the handlers, kept in memory,
+//! need no registering again. [`save_distributor`] and
+//! [`restore_distributor`] do the same for the SPIs, should the distributor
+//! lose power too.
+//!
+//! The ITS and the LPI tables in memory are not covered; a redistributor that
+//! lost power gets its LPI table addresses back.
+
+use alloc::{vec, vec::Vec};
+use core::{hint::spin_loop, ops::Range};
+
+use log::{debug, error, warn};
+use spin::Mutex;
+
+use super::{
+    ESPI_BASE, affinity, call, eppi_count, gic_version, intid_ranges, prio,
+    regs::{self, Frame, gicc, gicd, gicr},
+    this_cpu_idx, v2, v3,
+};
+
+/// Polls of `GICR_WAKER.ChildrenAsleep` before giving up.
+const WAKER_TIMEOUT: usize = 1 << 24;
+
+/// Where the registers of a block of INTIDs are, relative to their frame.
+#[derive(Clone, Copy)]
+struct Layout {
+    group: usize,
+    set_enable: usize,
+    clear_enable: usize,
+    priority: usize,
+    config: usize,
+    routing: Routing,
+}
+
+#[derive(Clone, Copy)]
+enum Routing {
+    /// Banked per CPU, nothing to route.
+    None,
+    /// GICv2 `GICD_ITARGETSR`, a byte per INTID.
+    Targets(usize),
+    /// GICv3 `GICD_IROUTER`, 64 bits per INTID.
+    Router(usize),
+}
+
+/// SGIs and PPIs of the GICv2 distributor, or the SGI frame of a GICv3
+/// redistributor, which has the same offsets.
+const PRIVATE: Layout = Layout {
+    group: gicr::IGROUPR,
+    set_enable: gicr::ISENABLER,
+    clear_enable: gicr::ICENABLER,
+    priority: gicr::IPRIORITYR,
+    config: gicr::ICFGR,
+    routing: Routing::None,
+};
+
+const fn spis(routing: Routing) -> Layout {
+    Layout {
+        group: gicd::IGROUPR,
+        set_enable: gicd::ISENABLER,
+        clear_enable: gicd::ICENABLER,
+        priority: gicd::IPRIORITYR,
+        config: gicd::ICFGR,
+        routing,
+    }
+}
+
+const ESPIS: Layout = Layout {
+    group: gicd::IGROUPRNE,
+    set_enable: gicd::ISENABLERNE,
+    clear_enable: gicd::ICENABLERNE,
+    priority: gicd::IPRIORITYRNE,
+    config: gicd::ICFGRNE,
+    routing: Routing::Router(gicd::IROUTERNE),
+};
+
+/// Saved registers of the INTIDs `range`, counted from the start of the
+/// register arrays of their [`Layout`].
+struct Bank {
+    range: Range<usize>,
+    group: Vec<u32>,
+    enable: Vec<u32>,
+    priority: Vec<u32>,
+    config: Vec<u32>,
+    routes: Vec<u64>,
+}
+
+/// Indices of the 32-bit registers covering `range`, at `per_reg` INTIDs each.
+fn regs_of(range: &Range<usize>, per_reg: usize) -> Range<usize> {
+    range.start / per_reg..range.end.div_ceil(per_reg)
+}
+
+impl Bank {
+    fn save(frame: Frame, layout: Layout, range: Range<usize>) -> Self {
+        let read = |base: usize, per_reg: usize| -> Vec<u32> {
+            regs_of(&range, per_reg)
+                .map(|i| frame.read32(base + 4 * i))
+                .collect()
+        };
+        let routes = match layout.routing {
+            Routing::None => Vec::new(),
+            Routing::Targets(base) => regs_of(&range, 4)
+                .map(|i| frame.read32(base + 4 * i) as u64)
+                .collect(),
+            Routing::Router(base) => range.clone().map(|n| frame.read64(base + 8 * n)).collect(),
+        };
+        Self {
+            group: read(layout.group, 32),
+            enable: read(layout.set_enable, 32),
+            priority: read(layout.priority, 4),
+            config: read(layout.config, 16),
+            routes,
+            range,
+        }
+    }
+
+    /// Writes the registers back. The INTIDs stay disabled until the rest of
+    /// their configuration is in place.
+    fn restore(&self, frame: Frame, layout: Layout) {
+        let write = |base: usize, per_reg: usize, vals: &[u32]| {
+            for (i, &val) in regs_of(&self.range, per_reg).zip(vals) {
+                frame.write32(base + 4 * i, val);
+            }
+        };
+        write(layout.clear_enable, 32, &vec![u32::MAX; self.enable.len()]);
+        write(layout.group, 32, &self.group);
+        write(layout.priority, 4, &self.priority);
+        write(layout.config, 16, &self.config);
+        match layout.routing {
+            Routing::None => {}
+            Routing::Targets(base) => {
+                for (i, &val) in regs_of(&self.range, 4).zip(&self.routes) {
+                    frame.write32(base + 4 * i, val as u32);
+                }
+            }
+            Routing::Router(base) => {
+                for (n, &val) in self.range.clone().zip(&self.routes) {
+                    frame.write64(base + 8 * n, val);
+                }
+            }
+        }
+        write(layout.set_enable, 32, &self.enable);
+    }
+}
+
+struct CpuState {
+    private: Bank,
+    pmr: u8,
+    /// `GICR_PROPBASER` and `GICR_PENDBASER`, if LPIs were enabled.
+    lpi_tables: Option<(u64, u64)>,
+}
+
+/// State of the current CPU while suspended.
+#[percpu::def_percpu]
+static CPU_STATE: Mutex<Option<CpuState>> = Mutex::new(None);
+
+struct DistState {
+    ctlr: u32,
+    banks: Vec<(Layout, Bank)>,
+}
+
+static DIST_STATE: Mutex<Option<DistState>> = Mutex::new(None);
+
+/// Returns the frame with the current CPU's private INTIDs.
+fn private_frame() -> Frame {
+    match gic_version() {
+        2 => regs::gicd(),
+        3 => regs::gicr_sgi(),
+        _ => panic!("Unsupported GIC version"),
+    }
+}
+
+fn private_count() -> usize {
+    match gic_version() {
+        3 => 32 + eppi_count(),
+        _ => 32,
+    }
+}
+
+fn read_pmr() -> u8 {
+    match gic_version() {
+        2 => regs::gicc().read32(gicc::PMR) as u8,
+        3 => regs::read_icc_pmr(),
+        _ => panic!("Unsupported GIC version"),
+    }
+}
+
+fn write_pmr(pmr: u8) {
+    match gic_version() {
+        2 => regs::gicc().write32(gicc::PMR, pmr as u32),
+        3 => regs::write_icc_pmr(pmr),
+        _ => panic!("Unsupported GIC version"),
+    }
+}
+
+/// Sets or clears `GICR_WAKER.ProcessorSleep` of the current CPU and waits
+/// until the redistributor agrees.
+///
+/// With two security states (`GICD_CTLR.DS` clear, as firmware usually sets
+/// it up) the bit belongs to the secure side and ignores the write; nothing
+/// is waited for then.
+fn set_processor_sleep(sleep: bool) {
+    let rd = regs::gicr();
+    let waker = rd.read32(gicr::WAKER);
+    let waker = if sleep {
+        waker | gicr::WAKER_PROCESSOR_SLEEP
+    } else {
+        waker & !gicr::WAKER_PROCESSOR_SLEEP
+    };
+    rd.write32(gicr::WAKER, waker);
+    if (rd.read32(gicr::WAKER) & gicr::WAKER_PROCESSOR_SLEEP != 0) != sleep {
+        debug!("GICR_WAKER.ProcessorSleep is not writable");
+        return;
+    }
+    for _ in 0..WAKER_TIMEOUT {
+        if (rd.read32(gicr::WAKER) & gicr::WAKER_CHILDREN_ASLEEP != 0) == sleep {
+            return;
+        }
+        spin_loop();
+    }
+    error!(
+        "redistributor of CPU {} did not {}",
+        this_cpu_idx(),
+        if sleep { "go to sleep" } else { "wake up" }
+    );
+}
+
+/// Saves the GIC state of the current CPU and quiesces its CPU interface,
+/// before the CPU is suspended or powered off with local IRQs disabled.
+///
+/// The CPU stops taking part in [cross-CPU calls](super::call_on_all) and
+/// its queued calls are run now. SPIs routed only to it wait until it
+/// resumes.
+pub fn suspend_current_cpu() {
+    let cpu = this_cpu_idx();
+    affinity::set_offline(cpu);
+    call::close_current_cpu();
+
+    let lpi_tables = (gic_version() == 3)
+        .then(regs::gicr)
+        .filter(|rd| rd.read32(gicr::CTLR) & gicr::CTLR_ENABLE_LPIS != 0)
+        .map(|rd| (rd.read64(gicr::PROPBASER), rd.read64(gicr::PENDBASER)));
+    let state = CpuState {
+        private: Bank::save(private_frame(), PRIVATE, 0..private_count()),
+        pmr: read_pmr(),
+        lpi_tables,
+    };
+    CPU_STATE.with_current(|s| *s.lock() = Some(state));
+
+    match gic_version() {
+        2 => {
+            let cpu_if = regs::gicc();
+            cpu_if.write32(gicc::CTLR, cpu_if.read32(gicc::CTLR) & !1);
+        }
+        3 => {
+            regs::write_icc_igrpen1(false);
+            set_processor_sleep(true);
+        }
+        _ => panic!("Unsupported GIC version"),
+    }
+    debug!("GIC state of CPU {cpu} saved");
+}
+
+/// Brings the GIC state of the current CPU back after
+/// [`suspend_current_cpu`], whether or not it was lost.
+pub fn resume_current_cpu() {
+    let cpu = this_cpu_idx();
+    let Some(state) = CPU_STATE.with_current(|s| s.lock().take()) else {
+        warn!("CPU {cpu} resumed without a saved GIC state");
+        return;
+    };
+    match gic_version() {
+        2 => v2::init_current_cpu(),
+        3 => {
+            set_processor_sleep(false);
+            v3::init_cpu_if();
+            let rd = regs::gicr();
+            if let Some((prop, pend)) = state.lpi_tables
+                && rd.read32(gicr::CTLR) & gicr::CTLR_ENABLE_LPIS == 0
+            {
+                rd.write64(gicr::PROPBASER, prop);
+                rd.write64(gicr::PENDBASER, pend);
+                rd.write32(gicr::CTLR, rd.read32(gicr::CTLR) | gicr::CTLR_ENABLE_LPIS);
+            }
+        }
+        _ => panic!("Unsupported GIC version"),
+    }
+    state.private.restore(private_frame(), PRIVATE);
+    prio::init_current_cpu();
+    write_pmr(state.pmr);
+    call::open_current_cpu();
+    affinity::set_online(cpu);
+    debug!("GIC state of CPU {cpu} restored");
+}
+
+fn wait_rwp() {
+    if gic_version() == 3 {
+        while regs::gicd().read32(gicd::CTLR) & gicd::CTLR_RWP != 0 {
+            spin_loop();
+        }
+    }
+}
+
+/// Saves the configuration of every SPI, extended SPIs included, before the
+/// distributor loses power.
+pub fn save_distributor() {
+    let dist = regs::gicd();
+    let routing = match gic_version() {
+        2 => Routing::Targets(gicd::ITARGETSR),
+        3 => Routing::Router(gicd::IROUTER),
+        _ => panic!("Unsupported GIC version"),
+    };
+    let mut banks = Vec::new();
+    for range in intid_ranges() {
+        if range.start == 0 && range.end > 32 {
+            banks.push((
+                spis(routing),
+                Bank::save(dist, spis(routing), 32..range.end),
+            ));
+        } else if range.start == ESPI_BASE {
+            banks.push((ESPIS, Bank::save(dist, ESPIS, 0..range.len())));
+        }
+    }
+    *DIST_STATE.lock() = Some(DistState {
+        ctlr: dist.read32(gicd::CTLR),
+        banks,
+    });
+    debug!("GIC distributor state saved");
+}
+
+/// Restores the state from [`save_distributor`], the distributor staying
+/// disabled meanwhile.
+pub fn restore_distributor() {
+    let Some(state) = DIST_STATE.lock().take() else {
+        warn!("no saved GIC distributor state");
+        return;
+    };
+    let dist = regs::gicd();
+    // Affinity routing is set up before the groups are enabled again.
+    dist.write32(gicd::CTLR, state.ctlr & !gicd::CTLR_ENABLE_GRPS);
+    wait_rwp();
+    for (layout, bank) in &state.banks {
+        bank.restore(dist, *layout);
+    }
+    wait_rwp();
+    dist.write32(gicd::CTLR, state.ctlr);
+    wait_rwp();
+    debug!("GIC distributor state restored");
+}
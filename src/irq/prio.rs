@@ -0,0 +1,108 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::{
+    EPPI_BASE, ESPI_BASE, IrqError, eppi_count, gic_version, is_eppi, is_espi,
+    regs::{self, Frame, gicc, gicd, gicr},
+};
+
+/// Priority interrupts are expected to run at unless configured otherwise.
+/// Lower values are more urgent.
+pub const DEFAULT_PRIORITY: u8 = 0xa0;
+
+/// Binary point making priority bits [7:3] the group priority, so interrupts
+/// at least 8 levels more urgent preempt a running handler.
+const BPR_NESTED: u8 = 2;
+/// Binary point without group priority bits: nothing preempts.
+const BPR_FLAT: u8 = 7;
+
+static NESTED: AtomicBool = AtomicBool::new(false);
+
+/// Returns the register frame and offset of the priority byte of `irq`.
+fn priority_reg(irq: usize) -> Result<(Frame, usize), IrqError> {
+    match irq {
+        0..32 if gic_version() == 3 => Ok((regs::gicr_sgi(), gicr::IPRIORITYR + irq)),
+        0..1020 => Ok((regs::gicd(), gicd::IPRIORITYR + irq)),
+        _ if is_eppi(irq) && irq - EPPI_BASE < eppi_count() => Ok((
+            regs::gicr_sgi(),
+            gicr::IPRIORITYR + irq - EPPI_BASE + 32,
+        )),
+        _ if is_espi(irq) => Ok((regs::gicd(), gicd::IPRIORITYRNE + irq - ESPI_BASE)),
+        _ => Err(IrqError::InvalidIrq),
+    }
+}
+
+/// Sets the priority of `irq`. Lower values are more urgent; how many low
+/// bits are ignored depends on the GIC implementation.
+///
+/// SGIs and PPIs are banked per CPU, so for them only the current CPU is
+/// configured.
+pub fn set_priority(irq: usize, prio: u8) -> Result<(), IrqError> {
+    let (frame, off) = priority_reg(irq)?;
+    frame.write8(off, prio);
+    Ok(())
+}
+
+/// Returns the priority of `irq`, as seen from the current CPU.
+pub fn priority(irq: usize) -> Result<u8, IrqError> {
+    let (frame, off) = priority_reg(irq)?;
+    Ok(frame.read8(off))
+}
+
+/// Sets the priority mask of the current CPU: only interrupts more urgent than
+/// `mask` are signaled to it.
+///
+/// In pseudo-NMI mode, `mask` applies while interrupts are locally enabled.
+pub fn set_priority_mask(mask: u8) {
+    #[cfg(feature = "pseudo-nmi")]
+    if super::nmi::set_irq_on_mask(mask) {
+        return;
+    }
+    match gic_version() {
+        2 => regs::gicc().write32(gicc::PMR, mask as u32),
+        3 => regs::write_icc_pmr(mask),
+        _ => panic!("Unsupported GIC version"),
+    }
+}
+
+/// Returns the priority mask of the current CPU.
+pub fn priority_mask() -> u8 {
+    match gic_version() {
+        2 => regs::gicc().read32(gicc::PMR) as u8,
+        3 => regs::read_icc_pmr(),
+        _ => panic!("Unsupported GIC version"),
+    }
+}
+
+/// Enables or disables nested interrupt handling.
+///
+/// When enabled, handlers run with interrupts unmasked, and an interrupt whose
+/// priority is at least 8 levels more urgent than the one being handled (e.g.
+/// the timer over a slow device) preempts it. The binary point of the online
+/// CPUs is updated right away through a [cross-CPU call](super::call_on_all);
+/// the other CPUs pick it up when their GIC interface is initialized.
+pub fn set_nested(enable: bool) {
+    NESTED.store(enable, Ordering::SeqCst);
+    if super::call_on_all(apply_binary_point).is_err() {
+        apply_binary_point();
+    }
+}
+
+/// Returns `true` if nested interrupt handling is enabled.
+pub fn nested() -> bool {
+    NESTED.load(Ordering::Relaxed)
+}
+
+fn apply_binary_point() {
+    // Pseudo-NMIs preempt the handlers they interrupt.
+    let preempt = nested() || cfg!(feature = "pseudo-nmi") && gic_version() == 3;
+    let bpr = if preempt { BPR_NESTED } else { BPR_FLAT };
+    match gic_version() {
+        2 => regs::gicc().write32(gicc::BPR, bpr as u32),
+        3 => regs::write_icc_bpr1(bpr),
+        _ => panic!("Unsupported GIC version"),
+    }
+}
+
+pub(super) fn init_current_cpu() {
+    apply_binary_point();
+}
@@ -0,0 +1,166 @@
+//! Per-CPU handlers of the private interrupts: SGIs, PPIs and extended PPIs.
+//!
+//! Each CPU dispatches its private interrupts from its own table, so a PPI
+//! such as the timer or the PMU can have a different handler on each core.
+//! Handlers registered for all CPUs are also kept in the private slots of the
+//! global table, from which CPUs brought up later take them.
+//!
+//! Registering for all CPUs writes the other CPUs' tables directly, through
+//! their per-CPU areas, without interrupting them: it is done at boot with
+//! IRQs masked, where a cross-CPU call could not be waited for.
+
+use axplat::irq::IrqHandler;
+use lazyinit::LazyInit;
+use log::debug;
+use spin::Mutex;
+
+use super::{
+    EPPI_BASE, IRQ_HANDLER_TABLE, IrqError, affinity, cpu_count, table::IrqTable, this_cpu_idx,
+};
+
+/// CPUs a private handler is registered on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuTarget {
+    /// The current CPU only.
+    Current,
+    /// Every CPU, including those brought up later.
+    All,
+}
+
+/// Private handlers of the current CPU.
+#[percpu::def_percpu]
+static HANDLERS: LazyInit<IrqTable> = LazyInit::new();
+
+/// Serializes registrations, which span several CPUs, with the CPUs coming
+/// up and copying the global table.
+static REGISTER_LOCK: Mutex<()> = Mutex::new(());
+
+/// Builds the current CPU's table, with the handlers registered for all CPUs,
+/// and marks the CPU online.
+///
+/// A CPU coming back from hotplug keeps the table it built first, which
+/// registrations for all CPUs kept up to date meanwhile.
+pub(super) fn init_current_cpu() {
+    let _guard = REGISTER_LOCK.lock();
+    if let Some(global) = IRQ_HANDLER_TABLE.get()
+        && !HANDLERS.with_current(|h| h.is_inited())
+    {
+        let table = build_table(global);
+        HANDLERS.with_current(|h| {
+            h.init_once(table);
+        });
+    }
+    // Online only now, so that no registration for all CPUs misses it.
+    affinity::set_online(this_cpu_idx());
+}
+
+/// Returns a table of the private interrupts of `global`, with their
+/// handlers registered for all CPUs.
+fn build_table(global: &IrqTable) -> IrqTable {
+    let ranges = global.ranges().filter_map(|r| match r.start {
+        0 => Some(0..32),
+        EPPI_BASE => Some(r),
+        _ => None,
+    });
+    let table = IrqTable::new(ranges);
+    for irq in table.ranges().flatten() {
+        if let Some(handler) = global.handler(irq) {
+            table.register_handler(irq, handler);
+        }
+    }
+    table
+}
+
+fn with_table<R>(f: impl FnOnce(&IrqTable) -> R) -> Option<R> {
+    HANDLERS.with_current(|h| h.get().map(f))
+}
+
+/// Returns the tables of the CPUs that have built theirs. The caller holds
+/// [`REGISTER_LOCK`], so no table is being built meanwhile.
+///
+/// The per-CPU areas are indexed by logical CPU ID.
+fn built_tables() -> impl Iterator<Item = &'static IrqTable> {
+    (0..cpu_count()).filter_map(|cpu| {
+        // SAFETY: the tables are only built under `REGISTER_LOCK` and never
+        // dropped; once built, their slots are atomics shared with the
+        // CPU's interrupt handler anyway.
+        unsafe { HANDLERS.remote_ref_raw(cpu) }.get()
+    })
+}
+
+/// Registers `handler` for the private interrupt `irq` on `target`.
+///
+/// It fails if `irq` already has a handler on one of those CPUs, or for all
+/// CPUs. The interrupt is not enabled.
+pub fn register_private(
+    irq: usize,
+    handler: IrqHandler,
+    target: CpuTarget,
+) -> Result<(), IrqError> {
+    let global = IRQ_HANDLER_TABLE.get().ok_or(IrqError::InvalidIrq)?;
+    if !super::is_private(irq) || !global.implements(irq) {
+        return Err(IrqError::InvalidIrq);
+    }
+    let _guard = REGISTER_LOCK.lock();
+    if global.is_registered(irq) {
+        return Err(IrqError::Busy);
+    }
+    match target {
+        CpuTarget::Current => {
+            let ok = with_table(|t| t.register_handler(irq, handler));
+            match ok {
+                Some(true) => Ok(()),
+                Some(false) => Err(IrqError::Busy),
+                None => Err(IrqError::InvalidCpu),
+            }
+        }
+        CpuTarget::All => {
+            if built_tables().any(|t| t.is_registered(irq)) {
+                return Err(IrqError::Busy);
+            }
+            global.register_handler(irq, handler);
+            for table in built_tables() {
+                table.register_handler(irq, handler);
+            }
+            debug!("IRQ {irq}: handler registered on all CPUs");
+            Ok(())
+        }
+    }
+}
+
+/// Removes the handler of the private interrupt `irq` from `target` and
+/// returns it. The interrupt is not disabled.
+///
+/// A handler registered for all CPUs can only be removed from all of them.
+pub fn unregister_private(irq: usize, target: CpuTarget) -> Result<IrqHandler, IrqError> {
+    let global = IRQ_HANDLER_TABLE.get().ok_or(IrqError::InvalidIrq)?;
+    if !super::is_private(irq) {
+        return Err(IrqError::InvalidIrq);
+    }
+    let _guard = REGISTER_LOCK.lock();
+    match target {
+        CpuTarget::Current => {
+            if global.is_registered(irq) {
+                return Err(IrqError::Busy);
+            }
+            with_table(|t| t.unregister_handler(irq))
+                .flatten()
+                .ok_or(IrqError::NotRegistered)
+        }
+        CpuTarget::All => {
+            let handler = global
+                .unregister_handler(irq)
+                .ok_or(IrqError::NotRegistered)?;
+            for table in built_tables() {
+                table.unregister_handler(irq);
+            }
+            Ok(handler)
+        }
+    }
+}
+
+/// Calls the current CPU's handler of the private interrupt `irq`. Returns
+/// `false` if it has none.
+pub(super) fn handle(irq: usize) -> bool {
+    with_table(|t| t.handle(irq)).unwrap_or(false)
+}
@@ -0,0 +1,185 @@
+//! Raw access to the GIC registers that `arm_gic_driver` does not expose.
+//!
+//! The frames are located through the GIC node of the device tree and reached
+//! through the linear mapping, as every device `reg` window is in
+//! [`MemIf::mmio_ranges`](axplat::mem::MemIf::mmio_ranges).
+
+use core::arch::asm;
+
+use aarch64_cpu::registers::*;
+use axplat::mem::{phys_to_virt, virt_to_phys};
+use spin::Once;
+
+use crate::info::{GicInfo, probe_gic};
+
+pub mod gicd {
+    pub const CTLR: usize = 0x0000;
+    /// Group enable bits, which must be clear while the ARE bits change.
+    pub const CTLR_ENABLE_GRPS: u32 = 0b11;
+    pub const CTLR_RWP: u32 = 1 << 31;
+    pub const TYPER: usize = 0x0004;
+    pub const TYPER_ESPI: u32 = 1 << 8;
+    pub const TYPER_LPIS: u32 = 1 << 17;
+    pub const IGROUPR: usize = 0x0080;
+    pub const ISENABLER: usize = 0x0100;
+    pub const ICENABLER: usize = 0x0180;
+    pub const IPRIORITYR: usize = 0x0400;
+    pub const ITARGETSR: usize = 0x0800;
+    pub const ICFGR: usize = 0x0c00;
+    pub const IROUTER: usize = 0x6000;
+    pub const IGROUPRNE: usize = 0x1000;
+    pub const ISENABLERNE: usize = 0x1200;
+    pub const ICENABLERNE: usize = 0x1400;
+    pub const IPRIORITYRNE: usize = 0x2000;
+    pub const ICFGRNE: usize = 0x3000;
+    pub const IROUTERNE: usize = 0x8000;
+}
+
+pub mod gicc {
+    pub const CTLR: usize = 0x0000;
+    pub const PMR: usize = 0x0004;
+    pub const BPR: usize = 0x0008;
+}
+
+pub mod gicr {
+    pub const CTLR: usize = 0x0000;
+    pub const CTLR_ENABLE_LPIS: u32 = 1 << 0;
+    pub const TYPER: usize = 0x0008;
+    pub const TYPER_VLPIS: u64 = 1 << 1;
+    pub const TYPER_LAST: u64 = 1 << 4;
+    pub const WAKER: usize = 0x0014;
+    pub const WAKER_PROCESSOR_SLEEP: u32 = 1 << 1;
+    pub const WAKER_CHILDREN_ASLEEP: u32 = 1 << 2;
+    pub const PROPBASER: usize = 0x0070;
+    pub const PENDBASER: usize = 0x0078;
+    /// Offset of the SGI/PPI frame from the redistributor base.
+    pub const SGI_BASE: usize = 0x1_0000;
+    // Relative to `SGI_BASE`. The extended PPIs follow the PPIs in each
+    // array, as if they were INTIDs 32 to 95.
+    pub const IGROUPR: usize = 0x0080;
+    pub const ISENABLER: usize = 0x0100;
+    pub const ICENABLER: usize = 0x0180;
+    pub const IPRIORITYR: usize = 0x0400;
+    pub const ICFGR: usize = 0x0c00;
+}
+
+static GIC: Once<GicInfo> = Once::new();
+
+#[percpu::def_percpu]
+static GICR_BASE: usize = 0;
+
+/// A memory-mapped register frame.
+#[derive(Debug, Clone, Copy)]
+pub struct Frame(usize);
+
+impl Frame {
+    pub fn at(paddr: usize) -> Self {
+        Self(phys_to_virt(paddr.into()).as_usize())
+    }
+
+    /// Returns the physical address of the frame.
+    pub fn paddr(&self) -> usize {
+        virt_to_phys(self.0.into()).as_usize()
+    }
+
+    /// Returns the frame at `offset` from this one.
+    pub fn offset(&self, offset: usize) -> Self {
+        Self(self.0 + offset)
+    }
+
+    pub fn read8(&self, off: usize) -> u8 {
+        unsafe { ((self.0 + off) as *const u8).read_volatile() }
+    }
+
+    pub fn write8(&self, off: usize, val: u8) {
+        unsafe { ((self.0 + off) as *mut u8).write_volatile(val) }
+    }
+
+    pub fn read32(&self, off: usize) -> u32 {
+        unsafe { ((self.0 + off) as *const u32).read_volatile() }
+    }
+
+    pub fn write32(&self, off: usize, val: u32) {
+        unsafe { ((self.0 + off) as *mut u32).write_volatile(val) }
+    }
+
+    pub fn read64(&self, off: usize) -> u64 {
+        unsafe { ((self.0 + off) as *const u64).read_volatile() }
+    }
+
+    pub fn write64(&self, off: usize, val: u64) {
+        unsafe { ((self.0 + off) as *mut u64).write_volatile(val) }
+    }
+}
+
+pub fn init() {
+    GIC.call_once(|| probe_gic().expect("GIC not found in the device tree"));
+}
+
+pub fn info() -> &'static GicInfo {
+    GIC.get().expect("GIC registers are not initialized")
+}
+
+/// Returns the distributor.
+pub fn gicd() -> Frame {
+    Frame::at(info().gicd.0)
+}
+
+/// Returns the GICv2 CPU interface.
+pub fn gicc() -> Frame {
+    Frame::at(info().gicc.expect("GICv2 CPU interface not found").0)
+}
+
+/// Locates the redistributor of the current CPU. GICv3 only.
+pub fn locate_gicr() {
+    let mpidr = MPIDR_EL1.get();
+    let affinity = (mpidr & 0xff_ffff) | ((mpidr >> 32) & 0xff) << 24;
+    for &(base, size) in &info().gicr {
+        let mut off = 0;
+        while off < size {
+            let rd = Frame::at(base + off);
+            let typer = rd.read64(gicr::TYPER);
+            if typer >> 32 == affinity {
+                GICR_BASE.write_current(rd.0);
+                return;
+            }
+            if typer & gicr::TYPER_LAST != 0 {
+                break;
+            }
+            off += if typer & gicr::TYPER_VLPIS != 0 {
+                0x4_0000
+            } else {
+                0x2_0000
+            };
+        }
+    }
+    panic!("no redistributor for MPIDR {mpidr:#x}");
+}
+
+/// Returns the redistributor of the current CPU. GICv3 only.
+pub fn gicr() -> Frame {
+    Frame(GICR_BASE.read_current())
+}
+
+/// Returns the SGI/PPI frame of the current CPU's redistributor. GICv3 only.
+pub fn gicr_sgi() -> Frame {
+    gicr().offset(gicr::SGI_BASE)
+}
+
+pub fn read_icc_pmr() -> u8 {
+    let val: u64;
+    unsafe { asm!("mrs {0}, ICC_PMR_EL1", out(reg) val) };
+    val as u8
+}
+
+pub fn write_icc_pmr(val: u8) {
+    unsafe { asm!("msr ICC_PMR_EL1, {0}", "dsb sy", in(reg) val as u64) };
+}
+
+pub fn write_icc_igrpen1(val: bool) {
+    unsafe { asm!("msr ICC_IGRPEN1_EL1, {0}", "isb", in(reg) val as u64) };
+}
+
+pub fn write_icc_bpr1(val: u8) {
+    unsafe { asm!("msr ICC_BPR1_EL1, {0}", "isb", in(reg) val as u64) };
+}
@@ -0,0 +1,97 @@
+//! Allocation of the 16 SGIs among the platform, the kernel and other
+//! components, so that each IPI ID has a single, named owner.
+
+use alloc::vec::Vec;
+
+use log::debug;
+use spin::Mutex;
+
+use super::{CALL_SGI, IrqError};
+
+/// Number of SGIs.
+pub const SGI_COUNT: usize = 16;
+/// SGI reserved for the scheduler to kick another CPU.
+pub const RESCHED_SGI: usize = 14;
+
+/// SGIs the platform reserves for itself, with their owners. They cannot be
+/// allocated nor freed.
+pub const PLATFORM_SGIS: [(usize, &str); 2] = [(RESCHED_SGI, "resched"), (CALL_SGI, "call")];
+
+/// Owner of each SGI.
+static OWNERS: Mutex<[Option<&'static str>; SGI_COUNT]> = Mutex::new({
+    let mut owners = [None; SGI_COUNT];
+    let mut i = 0;
+    while i < PLATFORM_SGIS.len() {
+        let (id, name) = PLATFORM_SGIS[i];
+        owners[id] = Some(name);
+        i += 1;
+    }
+    owners
+});
+
+fn is_platform(id: usize) -> bool {
+    PLATFORM_SGIS.iter().any(|&(sgi, _)| sgi == id)
+}
+
+/// Reserves the SGI `id` for `owner`. Fails if it is already taken, by the
+/// platform or anybody else.
+pub fn reserve_sgi(id: usize, owner: &'static str) -> Result<(), IrqError> {
+    let mut owners = OWNERS.lock();
+    let slot = owners.get_mut(id).ok_or(IrqError::InvalidIrq)?;
+    if let Some(old) = slot {
+        debug!("SGI {id} wanted by {owner}, taken by {old}");
+        return Err(IrqError::Busy);
+    }
+    *slot = Some(owner);
+    debug!("SGI {id} reserved by {owner}");
+    Ok(())
+}
+
+/// Allocates a free SGI for `owner`, the highest first, and returns its ID.
+pub fn alloc_sgi(owner: &'static str) -> Result<usize, IrqError> {
+    let mut owners = OWNERS.lock();
+    let id = (0..SGI_COUNT)
+        .rev()
+        .find(|&id| owners[id].is_none())
+        .ok_or(IrqError::NoSpace)?;
+    owners[id] = Some(owner);
+    debug!("SGI {id} allocated to {owner}");
+    Ok(id)
+}
+
+/// Releases an SGI that `owner` took with [`reserve_sgi`] or [`alloc_sgi`].
+/// Fails with [`IrqError::Busy`] if another owner has it.
+pub fn free_sgi(id: usize, owner: &'static str) -> Result<(), IrqError> {
+    if is_platform(id) {
+        return Err(IrqError::Busy);
+    }
+    let mut owners = OWNERS.lock();
+    let slot = owners.get_mut(id).ok_or(IrqError::InvalidIrq)?;
+    match *slot {
+        None => Err(IrqError::NotRegistered),
+        Some(old) if old != owner => {
+            debug!("SGI {id} freed by {owner}, owned by {old}");
+            Err(IrqError::Busy)
+        }
+        Some(_) => {
+            *slot = None;
+            debug!("SGI {id} freed by {owner}");
+            Ok(())
+        }
+    }
+}
+
+/// Returns the owner of the SGI `id`, if taken.
+pub fn sgi_owner(id: usize) -> Option<&'static str> {
+    *OWNERS.lock().get(id)?
+}
+
+/// Returns the SGIs taken, the platform's included, with their owners.
+pub fn sgi_owners() -> Vec<(usize, &'static str)> {
+    OWNERS
+        .lock()
+        .iter()
+        .enumerate()
+        .filter_map(|(id, owner)| Some((id, (*owner)?)))
+        .collect()
+}
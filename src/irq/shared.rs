@@ -0,0 +1,115 @@
+//! Interrupt lines shared by several devices, such as PCIe INTx.
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::ptr::fn_addr_eq;
+
+use log::{debug, trace};
+use spin::Mutex;
+
+use super::{IRQ_HANDLER_TABLE, IrqError, set_enable};
+
+/// Handler of a shared line. It gets the cookie it was registered with and
+/// returns `true` if its device raised the interrupt.
+pub type SharedHandler = fn(usize) -> bool;
+
+type Entry = (SharedHandler, usize);
+
+/// Handlers of each shared line. Lists are replaced rather than modified, so
+/// dispatch only holds the lock long enough to clone the `Arc`.
+static SHARED: Mutex<BTreeMap<usize, Arc<[Entry]>>> = Mutex::new(BTreeMap::new());
+
+/// Runs `f` on the shared lines with local IRQs disabled, as dispatch takes
+/// the same lock.
+fn with_shared<R>(f: impl FnOnce(&mut BTreeMap<usize, Arc<[Entry]>>) -> R) -> R {
+    crate::with_irqs_disabled(|| f(&mut SHARED.lock()))
+}
+
+/// Adds `handler` to the handlers of `irq`, to be called with `cookie`.
+///
+/// The line is enabled with its first handler. It fails if `irq` has an
+/// exclusive handler from [`IrqIf::register`](axplat::irq::IrqIf::register)
+/// or already has this handler and cookie.
+pub fn register_shared(
+    irq: usize,
+    handler: SharedHandler,
+    cookie: usize,
+) -> Result<(), IrqError> {
+    let table = IRQ_HANDLER_TABLE.get().ok_or(IrqError::InvalidIrq)?;
+    if !table.implements(irq) {
+        return Err(IrqError::InvalidIrq);
+    }
+    // Checked under the lock, which exclusive registrations take too.
+    let first = with_shared(|shared| {
+        if table.is_registered(irq) {
+            return Err(IrqError::Busy);
+        }
+        let old = shared.get(&irq).map_or(&[][..], |list| &list[..]);
+        if old.iter().any(|&(h, c)| fn_addr_eq(h, handler) && c == cookie) {
+            return Err(IrqError::Busy);
+        }
+        let mut list = Vec::with_capacity(old.len() + 1);
+        list.extend_from_slice(old);
+        list.push((handler, cookie));
+        let first = list.len() == 1;
+        shared.insert(irq, list.into());
+        Ok(first)
+    })?;
+    trace!("register shared handler IRQ {irq}, cookie {cookie:#x}");
+    if first {
+        set_enable(irq, true);
+    }
+    Ok(())
+}
+
+/// Removes a handler added by [`register_shared`]. The line is disabled when
+/// its last handler goes.
+pub fn unregister_shared(
+    irq: usize,
+    handler: SharedHandler,
+    cookie: usize,
+) -> Result<(), IrqError> {
+    let last = with_shared(|shared| {
+        let old = shared.get(&irq).ok_or(IrqError::NotRegistered)?;
+        let list: Vec<Entry> = old
+            .iter()
+            .copied()
+            .filter(|&(h, c)| !(fn_addr_eq(h, handler) && c == cookie))
+            .collect();
+        if list.len() == old.len() {
+            return Err(IrqError::NotRegistered);
+        }
+        let last = list.is_empty();
+        if last {
+            shared.remove(&irq);
+        } else {
+            shared.insert(irq, list.into());
+        }
+        Ok(last)
+    })?;
+    trace!("unregister shared handler IRQ {irq}, cookie {cookie:#x}");
+    if last {
+        debug!("IRQ {irq}: last shared handler removed");
+        set_enable(irq, false);
+    }
+    Ok(())
+}
+
+/// Runs `register`, registering an exclusive handler of `irq`, unless `irq`
+/// has shared handlers. Both are done under the lock, so that no shared
+/// handler gets in between.
+pub(super) fn register_exclusive(irq: usize, register: impl FnOnce() -> bool) -> bool {
+    with_shared(|shared| !shared.contains_key(&irq) && register())
+}
+
+/// Returns `true` if `irq` has shared handlers.
+pub(super) fn is_shared(irq: usize) -> bool {
+    with_shared(|shared| shared.contains_key(&irq))
+}
+
+/// Calls every handler of `irq`. Returns `true` if any of them handled it.
+pub(super) fn handle(irq: usize) -> bool {
+    let Some(list) = with_shared(|shared| shared.get(&irq).cloned()) else {
+        return false;
+    };
+    list.iter().fold(false, |handled, &(handler, cookie)| handler(cookie) | handled)
+}
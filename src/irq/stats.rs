@@ -0,0 +1,217 @@
+//! Interrupt counters, kept per CPU.
+
+use alloc::{boxed::Box, format, vec, vec::Vec};
+use core::{
+    fmt,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+};
+
+use spin::Once;
+
+use super::{
+    EPPI_BASE, ESPI_BASE, IRQ_HANDLER_TABLE, LPI_BASE, cpu_count, sgi_owner, this_cpu_idx, trigger,
+    v2m,
+};
+
+/// Counters of each CPU, by logical CPU ID.
+static ROWS: Once<Vec<Row>> = Once::new();
+
+/// Counters of one CPU. The per-IRQ ones are indexed like the handler table.
+struct Row {
+    handled: Box<[AtomicU32]>,
+    unhandled: Box<[AtomicU32]>,
+    spurious: AtomicU64,
+}
+
+impl Row {
+    fn new(len: usize) -> Self {
+        Self {
+            handled: (0..len).map(|_| AtomicU32::new(0)).collect(),
+            unhandled: (0..len).map(|_| AtomicU32::new(0)).collect(),
+            spurious: AtomicU64::new(0),
+        }
+    }
+}
+
+pub(super) fn init() {
+    let Some(table) = IRQ_HANDLER_TABLE.get() else {
+        return;
+    };
+    ROWS.call_once(|| (0..cpu_count()).map(|_| Row::new(table.slot_count())).collect());
+}
+
+fn row() -> Option<&'static Row> {
+    ROWS.get()?.get(this_cpu_idx())
+}
+
+/// Counts an acknowledged IRQ on the current CPU.
+pub(super) fn record(irq: usize, handled: bool) {
+    let Some(row) = row() else {
+        return;
+    };
+    let Some(idx) = IRQ_HANDLER_TABLE.get().and_then(|t| t.index(irq)) else {
+        return;
+    };
+    let counters = if handled { &row.handled } else { &row.unhandled };
+    counters[idx].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts a spurious acknowledge (INTID 1020 to 1023) on the current CPU.
+pub(super) fn record_spurious() {
+    if let Some(row) = row() {
+        row.spurious.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Counters of one IRQ, by logical CPU ID. They wrap around at `u32::MAX`.
+#[derive(Debug, Clone)]
+pub struct IrqCount {
+    pub irq: usize,
+    /// Times a handler took the IRQ.
+    pub handled: Vec<u32>,
+    /// Times the IRQ was acknowledged with nobody to handle it.
+    pub unhandled: Vec<u32>,
+}
+
+impl IrqCount {
+    /// Returns the number of times the IRQ fired on `cpu`.
+    pub fn count(&self, cpu: usize) -> u64 {
+        self.handled[cpu] as u64 + self.unhandled[cpu] as u64
+    }
+
+    /// Returns the number of times the IRQ fired on any CPU.
+    pub fn total(&self) -> u64 {
+        (0..self.handled.len()).map(|cpu| self.count(cpu)).sum()
+    }
+}
+
+/// A snapshot of the interrupt counters, from [`irq_stats`].
+///
+/// Its [`Display`](fmt::Display) output looks like Linux's `/proc/interrupts`.
+#[derive(Debug, Clone, Default)]
+pub struct IrqStats {
+    /// Counters of the IRQs that fired at least once, by INTID. IPIs are the
+    /// SGIs among them.
+    pub irqs: Vec<IrqCount>,
+    /// Spurious acknowledges, by logical CPU ID.
+    pub spurious: Vec<u64>,
+}
+
+impl IrqStats {
+    /// Returns the number of CPUs covered.
+    pub fn cpu_count(&self) -> usize {
+        self.spurious.len()
+    }
+
+    /// Iterates over the counters of the IPIs, by SGI number.
+    pub fn ipis(&self) -> impl Iterator<Item = &IrqCount> {
+        self.irqs.iter().filter(|c| c.irq < 16)
+    }
+
+    /// Returns the number of unhandled IRQs, by logical CPU ID.
+    pub fn unhandled(&self) -> Vec<u64> {
+        let mut sums = vec![0; self.cpu_count()];
+        for c in &self.irqs {
+            for (sum, &n) in sums.iter_mut().zip(&c.unhandled) {
+                *sum += n as u64;
+            }
+        }
+        sums
+    }
+}
+
+/// Takes a snapshot of the interrupt counters of every CPU. It is empty before
+/// the interrupt controller is initialized.
+pub fn irq_stats() -> IrqStats {
+    let (Some(rows), Some(table)) = (ROWS.get(), IRQ_HANDLER_TABLE.get()) else {
+        return IrqStats::default();
+    };
+    let irqs = table
+        .ranges()
+        .flatten()
+        .enumerate()
+        .map(|(idx, irq)| IrqCount {
+            irq,
+            handled: rows
+                .iter()
+                .map(|r| r.handled[idx].load(Ordering::Relaxed))
+                .collect(),
+            unhandled: rows
+                .iter()
+                .map(|r| r.unhandled[idx].load(Ordering::Relaxed))
+                .collect(),
+        })
+        .filter(|c| c.total() > 0)
+        .collect();
+    IrqStats {
+        irqs,
+        spurious: rows
+            .iter()
+            .map(|r| r.spurious.load(Ordering::Relaxed))
+            .collect(),
+    }
+}
+
+fn kind(irq: usize) -> &'static str {
+    match irq {
+        0..16 => "SGI",
+        16..32 => "PPI",
+        _ if irq >= LPI_BASE => "LPI",
+        _ if irq >= ESPI_BASE => "ESPI",
+        _ if irq >= EPPI_BASE => "EPPI",
+        _ => "SPI",
+    }
+}
+
+fn trigger_name(irq: usize) -> &'static str {
+    use arm_gic_driver::v3::Trigger;
+
+    match trigger(irq) {
+        Some(Trigger::Edge) => "Edge",
+        Some(Trigger::Level) => "Level",
+        None if irq >= LPI_BASE || v2m::is_msi(irq) => "Edge",
+        None => "-",
+    }
+}
+
+impl fmt::Display for IrqStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "      ")?;
+        for cpu in 0..self.cpu_count() {
+            write!(f, "{:>11}", format!("CPU{cpu}"))?;
+        }
+        writeln!(f)?;
+
+        for c in self.irqs.iter().filter(|c| c.irq >= 16) {
+            write_row(f, &format!("{}", c.irq), per_cpu(c))?;
+            writeln!(f, "  {:<4} {}", kind(c.irq), trigger_name(c.irq))?;
+        }
+        for c in self.ipis() {
+            write_row(f, &format!("IPI{}", c.irq), per_cpu(c))?;
+            match sgi_owner(c.irq) {
+                Some(owner) => writeln!(f, "  SGI  {owner}")?,
+                None => writeln!(f, "  SGI")?,
+            }
+        }
+        write_row(f, "UNH", self.unhandled())?;
+        writeln!(f, "  unhandled")?;
+        write_row(f, "SPU", self.spurious.iter().copied())?;
+        writeln!(f, "  spurious")
+    }
+}
+
+fn per_cpu(c: &IrqCount) -> impl Iterator<Item = u64> + '_ {
+    (0..c.handled.len()).map(|cpu| c.count(cpu))
+}
+
+fn write_row(
+    f: &mut fmt::Formatter<'_>,
+    label: &str,
+    counts: impl IntoIterator<Item = u64>,
+) -> fmt::Result {
+    write!(f, "{label:>5}:")?;
+    for n in counts {
+        write!(f, "{n:>11}")?;
+    }
+    Ok(())
+}
@@ -0,0 +1,225 @@
+//! Interrupt storm detection.
+//!
+//! A line storms when it fires [`StormConfig::max_unhandled`] times in a row
+//! with nobody handling it, or more than [`StormConfig::max_rate`] times
+//! within [`StormConfig::window_ms`]. A storming line is disabled until
+//! [`rearm_irq`] is called. SGIs are never disabled, nor are LPIs, which
+//! have no enable bit to clear without the ITS lock.
+//!
+//! Detection runs in the interrupt handler, which cannot take the locks of
+//! [`set_enable`](super::set_enable) nor log safely: the line is masked with
+//! a single write to its `ICENABLER` bit, and the report waits for
+//! [`report_storms`], to be called from thread context.
+
+use alloc::{boxed::Box, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU32, AtomicU64, Ordering};
+
+use aarch64_cpu::registers::*;
+use log::{error, info};
+use spin::Once;
+
+use super::{
+    EPPI_BASE, ESPI_BASE, IRQ_HANDLER_TABLE, LPI_BASE, gic_version, is_eppi, is_espi,
+    regs::{self, gicd, gicr},
+    set_enable,
+};
+
+/// Thresholds of the storm detection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StormConfig {
+    /// Interrupts in a row that nobody handled. 0 disables the check.
+    pub max_unhandled: u32,
+    /// Interrupts of one line within `window_ms`. 0 disables the check.
+    pub max_rate: u32,
+    /// Length of the rate window, in milliseconds.
+    pub window_ms: u32,
+}
+
+impl StormConfig {
+    /// Thresholds in effect unless changed by [`set_storm_config`].
+    pub const DEFAULT: Self = Self {
+        max_unhandled: 1000,
+        max_rate: 100_000,
+        window_ms: 100,
+    };
+}
+
+impl Default for StormConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+static MAX_UNHANDLED: AtomicU32 = AtomicU32::new(StormConfig::DEFAULT.max_unhandled);
+static MAX_RATE: AtomicU32 = AtomicU32::new(StormConfig::DEFAULT.max_rate);
+static WINDOW_MS: AtomicU32 = AtomicU32::new(StormConfig::DEFAULT.window_ms);
+
+/// State of each line, indexed like the handler table.
+static LINES: Once<Box<[Line]>> = Once::new();
+
+#[derive(Default)]
+struct Line {
+    /// Counter value at the start of the rate window.
+    window_start: AtomicU64,
+    /// Interrupts in the current rate window.
+    count: AtomicU32,
+    /// Interrupts in a row that nobody handled.
+    unhandled: AtomicU32,
+    storming: AtomicBool,
+    /// Why the line was disabled, not reported yet: one of the `WHY_*`.
+    why: AtomicU8,
+    /// Unhandled run or rate limit the line was disabled at.
+    why_count: AtomicU32,
+}
+
+const WHY_NONE: u8 = 0;
+const WHY_UNHANDLED: u8 = 1;
+const WHY_RATE: u8 = 2;
+
+pub(super) fn init() {
+    let Some(table) = IRQ_HANDLER_TABLE.get() else {
+        return;
+    };
+    LINES.call_once(|| (0..table.slot_count()).map(|_| Line::default()).collect());
+}
+
+fn line(irq: usize) -> Option<&'static Line> {
+    let idx = IRQ_HANDLER_TABLE.get()?.index(irq)?;
+    LINES.get()?.get(idx)
+}
+
+/// Sets the thresholds of the storm detection.
+pub fn set_storm_config(config: StormConfig) {
+    MAX_UNHANDLED.store(config.max_unhandled, Ordering::Relaxed);
+    MAX_RATE.store(config.max_rate, Ordering::Relaxed);
+    WINDOW_MS.store(config.window_ms, Ordering::Relaxed);
+}
+
+/// Returns the thresholds of the storm detection.
+pub fn storm_config() -> StormConfig {
+    StormConfig {
+        max_unhandled: MAX_UNHANDLED.load(Ordering::Relaxed),
+        max_rate: MAX_RATE.load(Ordering::Relaxed),
+        window_ms: WINDOW_MS.load(Ordering::Relaxed),
+    }
+}
+
+/// Accounts an interrupt of `irq` and disables the line if it storms.
+pub(super) fn check(irq: usize, handled: bool) {
+    if irq < 16 || irq >= LPI_BASE {
+        return;
+    }
+    let Some(line) = line(irq) else {
+        return;
+    };
+    let config = storm_config();
+
+    if handled {
+        line.unhandled.store(0, Ordering::Relaxed);
+    } else {
+        let run = line.unhandled.fetch_add(1, Ordering::Relaxed) + 1;
+        if config.max_unhandled != 0 && run >= config.max_unhandled {
+            disable(irq, line, WHY_UNHANDLED, run);
+            return;
+        }
+    }
+
+    if config.max_rate == 0 {
+        return;
+    }
+    let now = CNTPCT_EL0.get();
+    let window = CNTFRQ_EL0.get() * config.window_ms as u64 / 1000;
+    if now.wrapping_sub(line.window_start.load(Ordering::Relaxed)) >= window {
+        line.window_start.store(now, Ordering::Relaxed);
+        line.count.store(1, Ordering::Relaxed);
+    } else {
+        let count = line.count.fetch_add(1, Ordering::Relaxed) + 1;
+        if count > config.max_rate {
+            disable(irq, line, WHY_RATE, config.max_rate);
+        }
+    }
+}
+
+fn disable(irq: usize, line: &Line, why: u8, count: u32) {
+    if !line.storming.swap(true, Ordering::AcqRel) {
+        mask(irq);
+        line.why_count.store(count, Ordering::Relaxed);
+        line.why.store(why, Ordering::Release);
+    }
+}
+
+/// Disables `irq` by setting its bit in `GICD_ICENABLER`, `GICR_ICENABLER`
+/// or their extended variants. A single write, safe in the interrupt
+/// handler; the line's configuration is left alone.
+fn mask(irq: usize) {
+    let (frame, n, icenabler) = if is_eppi(irq) {
+        (regs::gicr_sgi(), irq - EPPI_BASE + 32, gicr::ICENABLER)
+    } else if is_espi(irq) {
+        (regs::gicd(), irq - ESPI_BASE, gicd::ICENABLERNE)
+    } else if irq < 32 && gic_version() == 3 {
+        (regs::gicr_sgi(), irq, gicr::ICENABLER)
+    } else {
+        (regs::gicd(), irq, gicd::ICENABLER)
+    };
+    frame.write32(icenabler + n / 32 * 4, 1 << (n % 32));
+}
+
+/// Logs the lines disabled as storming since the last call. Call it from
+/// thread context, e.g. periodically or after [`storming_irqs`] is not
+/// empty.
+pub fn report_storms() {
+    let (Some(table), Some(lines)) = (IRQ_HANDLER_TABLE.get(), LINES.get()) else {
+        return;
+    };
+    for (irq, line) in table.ranges().flatten().zip(lines.iter()) {
+        let count = line.why_count.load(Ordering::Relaxed);
+        match line.why.swap(WHY_NONE, Ordering::Acquire) {
+            WHY_UNHANDLED => error!("IRQ {irq} storming: {count} unhandled in a row, disabled"),
+            WHY_RATE => error!(
+                "IRQ {irq} storming: over {count} in {} ms, disabled",
+                WINDOW_MS.load(Ordering::Relaxed)
+            ),
+            _ => {}
+        }
+    }
+}
+
+/// Returns `true` if `irq` was disabled as storming and not re-armed since.
+pub fn is_storming(irq: usize) -> bool {
+    line(irq).is_some_and(|line| line.storming.load(Ordering::Acquire))
+}
+
+/// Returns the lines disabled as storming.
+pub fn storming_irqs() -> Vec<usize> {
+    let (Some(table), Some(lines)) = (IRQ_HANDLER_TABLE.get(), LINES.get()) else {
+        return Vec::new();
+    };
+    table
+        .ranges()
+        .flatten()
+        .zip(lines.iter())
+        .filter(|(_, line)| line.storming.load(Ordering::Acquire))
+        .map(|(irq, _)| irq)
+        .collect()
+}
+
+/// Re-enables a line disabled as storming, with fresh counters. Returns
+/// `false` if it was not storming.
+///
+/// A storming PPI is only disabled on the CPU it stormed on, and must be
+/// re-armed there.
+pub fn rearm_irq(irq: usize) -> bool {
+    let Some(line) = line(irq) else {
+        return false;
+    };
+    if !line.storming.load(Ordering::Acquire) {
+        return false;
+    }
+    line.unhandled.store(0, Ordering::Relaxed);
+    line.count.store(0, Ordering::Relaxed);
+    line.window_start.store(CNTPCT_EL0.get(), Ordering::Relaxed);
+    line.storming.store(false, Ordering::Release);
+    info!("IRQ {irq} re-armed");
+    set_enable(irq, true);
+    true
+}
@@ -0,0 +1,113 @@
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use axplat::irq::IrqHandler;
+
+/// A table of IRQ handlers with a slot for every INTID the GIC implements.
+///
+/// The INTID space has large holes (between SPIs, extended PPIs, extended
+/// SPIs and LPIs), so the slots are kept in one segment per range.
+pub struct IrqTable {
+    segments: Vec<Segment>,
+}
+
+struct Segment {
+    base: usize,
+    slots: Box<[AtomicUsize]>,
+}
+
+impl IrqTable {
+    pub fn new(ranges: impl IntoIterator<Item = Range<usize>>) -> Self {
+        Self {
+            segments: ranges
+                .into_iter()
+                .filter(|r| !r.is_empty())
+                .map(|r| Segment {
+                    base: r.start,
+                    slots: r.map(|_| AtomicUsize::new(0)).collect(),
+                })
+                .collect(),
+        }
+    }
+
+    fn slot(&self, irq: usize) -> Option<&AtomicUsize> {
+        self.segments
+            .iter()
+            .find(|s| (s.base..s.base + s.slots.len()).contains(&irq))
+            .map(|s| &s.slots[irq - s.base])
+    }
+
+    /// Iterates over the ranges of INTIDs covered.
+    pub fn ranges(&self) -> impl Iterator<Item = Range<usize>> + '_ {
+        self.segments
+            .iter()
+            .map(|s| s.base..s.base + s.slots.len())
+    }
+
+    /// Returns the number of INTIDs covered.
+    pub fn slot_count(&self) -> usize {
+        self.segments.iter().map(|s| s.slots.len()).sum()
+    }
+
+    /// Returns the position of `irq` among the INTIDs covered, for side
+    /// tables indexed like this one.
+    pub fn index(&self, irq: usize) -> Option<usize> {
+        let mut offset = 0;
+        for s in &self.segments {
+            if (s.base..s.base + s.slots.len()).contains(&irq) {
+                return Some(offset + irq - s.base);
+            }
+            offset += s.slots.len();
+        }
+        None
+    }
+
+    /// Returns `true` if `irq` has a slot.
+    pub fn implements(&self, irq: usize) -> bool {
+        self.slot(irq).is_some()
+    }
+
+    /// Returns `true` if `irq` has a handler.
+    pub fn is_registered(&self, irq: usize) -> bool {
+        self.slot(irq).is_some_and(|slot| slot.load(Ordering::Acquire) != 0)
+    }
+
+    /// Returns the handler of `irq`, if any.
+    pub fn handler(&self, irq: usize) -> Option<IrqHandler> {
+        let handler = self.slot(irq)?.load(Ordering::Acquire);
+        if handler == 0 {
+            return None;
+        }
+        Some(unsafe { core::mem::transmute::<usize, IrqHandler>(handler) })
+    }
+
+    /// Sets the handler of `irq`. Fails if `irq` has no slot or already has a
+    /// handler.
+    pub fn register_handler(&self, irq: usize, handler: IrqHandler) -> bool {
+        self.slot(irq).is_some_and(|slot| {
+            slot.compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        })
+    }
+
+    /// Clears the handler of `irq` and returns it.
+    pub fn unregister_handler(&self, irq: usize) -> Option<IrqHandler> {
+        let handler = self.slot(irq)?.swap(0, Ordering::AcqRel);
+        if handler == 0 {
+            return None;
+        }
+        Some(unsafe { core::mem::transmute::<usize, IrqHandler>(handler) })
+    }
+
+    /// Calls the handler of `irq`. Returns `false` if it has none.
+    pub fn handle(&self, irq: usize) -> bool {
+        let Some(handler) = self.handler(irq) else {
+            return false;
+        };
+        handler();
+        true
+    }
+}
@@ -5,9 +5,10 @@ use lazyinit::LazyInit;
 use log::*;
 use spin::Mutex;
 
-use crate::irq::{self, current_cpu};
-
-use super::IRQ_HANDLER_TABLE;
+use crate::irq::{
+    self,
+    affinity::{self, Route},
+};
 
 #[percpu::def_percpu]
 pub static CPU_IF: LazyInit<Mutex<CpuInterface>> = LazyInit::new();
@@ -31,6 +32,7 @@ pub fn init_current_cpu() {
 pub fn handle(_unused: usize) -> Option<usize> {
     let ack = TRAP.ack();
     if ack.is_special() {
+        irq::stats::record_spurious();
         return None;
     }
 
@@ -40,7 +42,7 @@ pub fn handle(_unused: usize) -> Option<usize> {
     }
     .to_u32() as usize;
 
-    if !IRQ_HANDLER_TABLE.handle(irq_num) {
+    if !irq::dispatch(irq_num) {
         warn!("Unhandled IRQ {ack:?}");
     }
 
@@ -73,9 +75,11 @@ pub(crate) fn set_enable(irq_raw: usize, trigger: Option<Trigger>, enabled: bool
             }
         });
     } else {
+        if enabled {
+            set_route(irq_raw, affinity::route(irq_raw));
+        }
         use_gicd(|gic| {
             gic.set_irq_enable(id, enabled);
-            gic.set_target_cpu(id, TargetList::new([current_cpu()].into_iter()));
             if let Some(t) = trigger {
                 gic.set_cfg(id, t);
             }
@@ -84,6 +88,19 @@ pub(crate) fn set_enable(irq_raw: usize, trigger: Option<Trigger>, enabled: bool
     debug!("IRQ({irq_raw:#x}) set enable done");
 }
 
+/// Points `GICD_ITARGETSR` of the SPI `irq_raw` at the CPUs of `route`.
+pub(super) fn set_route(irq_raw: usize, route: Route) {
+    let id = unsafe { IntId::raw(irq_raw as _) };
+    let mask = match route {
+        Route::Cpus(mask) => mask,
+        Route::Any => affinity::online_cpus(),
+    };
+    let targets = (0..usize::BITS as usize)
+        .filter(|&cpu| mask & (1 << cpu) != 0)
+        .map(irq::cpu_hw_id);
+    use_gicd(|gic| gic.set_target_cpu(id, TargetList::new(targets)));
+}
+
 pub fn send_ipi(id: usize, target: axplat::irq::IpiTarget) {
     use_gicd(|gic| {
         gic.send_sgi(
@@ -0,0 +1,122 @@
+//! GICv2m MSI frames: each frame owns a range of SPIs, raised by writing the
+//! SPI number to the frame's doorbell.
+
+use alloc::{vec, vec::Vec};
+
+use fdt_parser::Status;
+use log::*;
+use spin::{Mutex, Once};
+
+use super::{IrqError, MsiVector, regs::Frame};
+use crate::fdt::{is_compatible, prop_u32};
+
+const V2M_COMPATS: &[&str] = &["arm,gic-v2m-frame"];
+
+const MSI_TYPER: usize = 0x0008;
+const MSI_SETSPI_NS: usize = 0x0040;
+
+static FRAMES: Once<Vec<MsiFrame>> = Once::new();
+
+struct MsiFrame {
+    /// Physical address of `MSI_SETSPI_NS`.
+    doorbell: u64,
+    base_spi: usize,
+    count: usize,
+    /// Allocated SPIs, one bit each.
+    used: Mutex<Vec<u64>>,
+}
+
+impl MsiFrame {
+    fn contains(&self, irq: usize) -> bool {
+        (self.base_spi..self.base_spi + self.count).contains(&irq)
+    }
+
+    fn alloc(&self) -> Option<usize> {
+        let mut used = self.used.lock();
+        let idx = (0..self.count).find(|&i| used[i / 64] & (1 << (i % 64)) == 0)?;
+        used[idx / 64] |= 1 << (idx % 64);
+        Some(self.base_spi + idx)
+    }
+
+    fn free(&self, irq: usize) {
+        let idx = irq - self.base_spi;
+        self.used.lock()[idx / 64] &= !(1 << (idx % 64));
+    }
+}
+
+/// Discovers the v2m frames. The SPI range comes from `MSI_TYPER` unless the
+/// node overrides it with `arm,msi-base-spi` and `arm,msi-num-spis`.
+pub fn init() {
+    FRAMES.call_once(|| {
+        let fdt = crate::fdt();
+        let mut frames = Vec::new();
+        for node in fdt.all_nodes() {
+            if matches!(node.status(), Some(Status::Disabled))
+                || !is_compatible(&node, V2M_COMPATS)
+            {
+                continue;
+            }
+            let Some(base) = node
+                .reg()
+                .and_then(|mut regs| regs.next())
+                .map(|reg| reg.address as usize)
+            else {
+                continue;
+            };
+            let typer = Frame::at(base).read32(MSI_TYPER);
+            let base_spi = prop_u32(&node, "arm,msi-base-spi").unwrap_or((typer >> 16) & 0x3ff);
+            let count = prop_u32(&node, "arm,msi-num-spis").unwrap_or(typer & 0x3ff);
+            let (base_spi, count) = (base_spi as usize, count as usize);
+            if count == 0 || base_spi < 32 || base_spi + count > 1020 {
+                warn!("{}: bad SPI range {base_spi}+{count}, ignored", node.name());
+                continue;
+            }
+            info!("GICv2m frame at {base:#x}: SPI {base_spi}..{}", base_spi + count);
+            frames.push(MsiFrame {
+                doorbell: (base + MSI_SETSPI_NS) as u64,
+                base_spi,
+                count,
+                used: Mutex::new(vec![0; count.div_ceil(64)]),
+            });
+        }
+        frames
+    });
+}
+
+fn frames() -> &'static [MsiFrame] {
+    FRAMES.get().map_or(&[], |f| f.as_slice())
+}
+
+/// Returns `true` if the SPI `irq` belongs to a v2m frame. Such SPIs are
+/// edge-triggered.
+pub fn is_msi(irq: usize) -> bool {
+    frames().iter().any(|f| f.contains(irq))
+}
+
+/// Allocates a free SPI from the first frame that has one.
+pub fn alloc() -> Result<MsiVector, IrqError> {
+    if frames().is_empty() {
+        return Err(IrqError::Unsupported);
+    }
+    frames()
+        .iter()
+        .find_map(|f| {
+            let irq = f.alloc()?;
+            Some(MsiVector {
+                irq,
+                addr: f.doorbell,
+                data: irq as u32,
+            })
+        })
+        .ok_or(IrqError::NoSpace)
+}
+
+/// Releases an SPI from [`alloc`].
+pub fn free(irq: usize) -> Result<(), IrqError> {
+    let frame = frames()
+        .iter()
+        .find(|f| f.contains(irq))
+        .ok_or(IrqError::InvalidIrq)?;
+    frame.free(irq);
+    Ok(())
+}
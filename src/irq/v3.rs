@@ -5,9 +5,11 @@ use lazyinit::LazyInit;
 use log::*;
 use spin::Mutex;
 
-use crate::irq;
-
-use super::IRQ_HANDLER_TABLE;
+use crate::irq::{
+    self, DEFAULT_PRIORITY,
+    affinity::{self, Route},
+    regs::{self, gicd, gicr},
+};
 
 #[percpu::def_percpu]
 pub static CPU_IF: LazyInit<Mutex<CpuInterface>> = LazyInit::new();
@@ -18,7 +20,39 @@ fn use_gicd(f: impl FnOnce(&mut Gic)) {
     f(gic.typed_mut::<Gic>().expect("GICD is not initialized"));
 }
 
+/// Puts the extended SPIs in Group 1 at the default priority, disabled.
+pub fn init_espi(count: usize) {
+    let gicd = regs::gicd();
+    for word in 0..count / 32 {
+        gicd.write32(gicd::IGROUPRNE + word * 4, u32::MAX);
+        gicd.write32(gicd::ICENABLERNE + word * 4, u32::MAX);
+    }
+    for n in 0..count {
+        gicd.write8(gicd::IPRIORITYRNE + n, DEFAULT_PRIORITY);
+    }
+}
+
+/// Same as [`init_espi`] for the current CPU's extended PPIs.
+fn init_eppi() {
+    let sgi = regs::gicr_sgi();
+    for word in 1..1 + irq::eppi_count() / 32 {
+        sgi.write32(gicr::IGROUPR + word * 4, u32::MAX);
+        sgi.write32(gicr::ICENABLER + word * 4, u32::MAX);
+    }
+    for n in 32..32 + irq::eppi_count() {
+        sgi.write8(gicr::IPRIORITYR + n, DEFAULT_PRIORITY);
+    }
+}
+
 pub fn init_current_cpu() {
+    irq::regs::locate_gicr();
+    init_cpu_if();
+    init_eppi();
+    irq::its::init_current_cpu();
+}
+
+/// Sets up the current CPU's redistributor and CPU interface.
+pub fn init_cpu_if() {
     CPU_IF.with_current(|c| {
         let mut cpu = c.lock();
         cpu.init_current_cpu().unwrap();
@@ -30,6 +64,7 @@ pub fn init_current_cpu() {
 pub fn handle(_unused: usize) -> Option<usize> {
     let ack = TRAP.ack1();
     if ack.is_special() {
+        irq::stats::record_spurious();
         return None;
     }
 
@@ -37,7 +72,7 @@ pub fn handle(_unused: usize) -> Option<usize> {
 
     // let cpu_id = current_cpu();
     // info!("[{cpu_id}] IRQ {}", irq_num);
-    if !IRQ_HANDLER_TABLE.handle(irq_num) {
+    if !irq::dispatch(irq_num) {
         warn!("Unhandled IRQ {ack:?}");
     }
 
@@ -59,6 +94,11 @@ pub(crate) fn set_enable(irq_raw: usize, trigger: Option<Trigger>, enabled: bool
             None => String::new(),
         }
     );
+    if irq::is_eppi(irq_raw) || irq::is_espi(irq_raw) {
+        set_enable_ext(irq_raw, trigger, enabled);
+        debug!("IRQ({irq_raw:#x}) set enable done");
+        return;
+    }
     let id = unsafe { IntId::raw(irq_raw as _) };
     if id.is_private() {
         CPU_IF.with_current(|c| {
@@ -70,9 +110,11 @@ pub(crate) fn set_enable(irq_raw: usize, trigger: Option<Trigger>, enabled: bool
             }
         });
     } else {
+        if enabled {
+            set_route(irq_raw, affinity::route(irq_raw));
+        }
         use_gicd(|gic| {
             gic.set_irq_enable(id, enabled);
-            gic.set_target_cpu(id, Some(Affinity::current()));
             if let Some(t) = trigger {
                 gic.set_cfg(id, t);
             }
@@ -81,6 +123,76 @@ pub(crate) fn set_enable(irq_raw: usize, trigger: Option<Trigger>, enabled: bool
     debug!("IRQ({irq_raw:#x}) set enable done");
 }
 
+/// Configures an extended PPI or SPI. `arm_gic_driver` does not know about
+/// them, so the registers are written directly.
+fn set_enable_ext(irq_raw: usize, trigger: Option<Trigger>, enabled: bool) {
+    let (frame, n, isenabler, icenabler, icfgr) = if irq::is_eppi(irq_raw) {
+        (
+            regs::gicr_sgi(),
+            irq_raw - irq::EPPI_BASE + 32,
+            gicr::ISENABLER,
+            gicr::ICENABLER,
+            gicr::ICFGR,
+        )
+    } else {
+        (
+            regs::gicd(),
+            irq_raw - irq::ESPI_BASE,
+            gicd::ISENABLERNE,
+            gicd::ICENABLERNE,
+            gicd::ICFGRNE,
+        )
+    };
+    let (word, bit) = (n / 32 * 4, 1 << (n % 32));
+    if !enabled {
+        frame.write32(icenabler + word, bit);
+        return;
+    }
+    if let Some(t) = trigger {
+        let (off, edge) = (icfgr + n / 16 * 4, 2 << (n % 16 * 2));
+        let cfg = frame.read32(off);
+        frame.write32(
+            off,
+            match t {
+                Trigger::Edge => cfg | edge,
+                Trigger::Level => cfg & !edge,
+            },
+        );
+    }
+    if irq::is_espi(irq_raw) {
+        set_route(irq_raw, affinity::route(irq_raw));
+    }
+    frame.write32(isenabler + word, bit);
+}
+
+/// Points `GICD_IROUTER` of the SPI `irq_raw` at the CPU of `route`, or
+/// selects 1-of-N routing.
+pub(super) fn set_route(irq_raw: usize, route: Route) {
+    if irq::is_espi(irq_raw) {
+        const IROUTER_ANY: u64 = 1 << 31;
+        let val = match route {
+            Route::Cpus(mask) => {
+                let hw_id = irq::cpu_hw_id(mask.trailing_zeros() as usize) as u64;
+                // MPIDR layout, as GICD_IROUTER: Aff3 in bits [39:32].
+                (hw_id & 0xff_ffff) | (hw_id & (0xff << 32))
+            }
+            Route::Any => IROUTER_ANY,
+        };
+        let off = gicd::IROUTERNE + (irq_raw - irq::ESPI_BASE) * 8;
+        regs::gicd().write64(off, val);
+        return;
+    }
+    let id = unsafe { IntId::raw(irq_raw as _) };
+    let target = match route {
+        Route::Cpus(mask) => {
+            let hw_id = irq::cpu_hw_id(mask.trailing_zeros() as usize);
+            Some(Affinity::from_mpidr(hw_id as _))
+        }
+        Route::Any => None,
+    };
+    use_gicd(|gic| gic.set_target_cpu(id, target));
+}
+
 pub fn send_ipi(id: usize, target: axplat::irq::IpiTarget) {
     arm_gic_driver::v3::send_sgi(
         IntId::sgi(id as _),
@@ -8,31 +8,69 @@ extern crate alloc;
 
 use core::ptr::NonNull;
 
+use axcpu::asm::{disable_irqs, enable_irqs, irqs_enabled};
 use axplat::mem::phys_to_virt;
 use fdt_parser::Fdt;
 
 mod boot;
-mod console;
+mod cmdline;
+pub mod console;
 mod driver;
 mod fdt;
+mod info;
 mod init;
 #[cfg(feature = "irq")]
-mod irq;
+pub mod irq;
 mod mem;
 mod power;
 #[cfg(feature = "smp")]
 mod smp;
 mod time;
 
+pub use cmdline::{Cmdline, cmdline};
+pub use info::{ConsoleInfo, GicInfo, PlatformInfo, platform_info};
+pub use mem::{Initrd, ReservedMemory, initrd, reserved_memory, reserved_memory_regions};
+
 pub mod config {
     axconfig_macros::include_configs!(path_env = "AX_CONFIG_PATH", fallback = "axconfig.toml");
 }
 
+/// Returns the number of CPUs the platform brings up, after the device tree
+/// and `maxcpus=`.
+///
+/// Kernels should wait for this many CPUs rather than for the build-time
+/// `plat.cpu-num`: [`cpu_boot`](axplat::power::PowerIf::cpu_boot) does not
+/// start CPUs beyond it, so waiting for more hangs.
+pub fn cpu_num() -> usize {
+    #[cfg(feature = "smp")]
+    {
+        smp::cpu_count()
+    }
+    #[cfg(not(feature = "smp"))]
+    {
+        1
+    }
+}
+
+/// Runs `f` with local IRQs disabled, then restores them as they were.
+pub(crate) fn with_irqs_disabled<R>(f: impl FnOnce() -> R) -> R {
+    let irqs = irqs_enabled();
+    disable_irqs();
+    let ret = f();
+    if irqs {
+        enable_irqs();
+    }
+    ret
+}
+
 fn fdt() -> Fdt<'static> {
     let paddr = somehal::boot_info()
         .fdt
         .expect("FDT is not available, please check the bootloader configuration");
-    let addr = phys_to_virt((paddr.as_ptr() as usize).into());
+    fdt_at(paddr.as_ptr() as usize).expect("Failed to parse FDT")
+}
 
-    Fdt::from_ptr(NonNull::new(addr.as_mut_ptr()).unwrap()).expect("Failed to parse FDT")
+fn fdt_at(paddr: usize) -> Option<Fdt<'static>> {
+    let addr = phys_to_virt(paddr.into());
+    Fdt::from_ptr(NonNull::new(addr.as_mut_ptr())?).ok()
 }
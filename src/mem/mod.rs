@@ -0,0 +1,362 @@
+use core::ops::Range;
+
+use axplat::mem::{MemIf, PhysAddr, RawRange, VirtAddr};
+use fdt_parser::Status;
+use log::{debug, info, trace, warn};
+use memory_addr::MemoryAddr;
+use somehal::{KIMAGE_VADDR, KIMAGE_VSIZE, KLINER_OFFSET, MemoryRegionKind, boot_info};
+use spin::Once;
+
+use crate::{
+    cmdline::{cmdline, parse_size},
+    fdt::{prop_strs, prop_uint},
+};
+
+use self::{
+    region::RegionList,
+    reserved::{MAX_RESERVED_NODES, ReservedMemory},
+};
+
+mod region;
+mod reserved;
+
+pub use reserved::{reserved_memory, reserved_memory_regions};
+
+/// Capacity of each region list, counted after merging.
+const MAX_REGIONS: usize = 128;
+
+struct MemIfImpl;
+
+static RAM_LIST: Once<RegionList<MAX_REGIONS>> = Once::new();
+static RESERVED_LIST: Once<RegionList<MAX_REGIONS>> = Once::new();
+static MMIO: Once<RegionList<MAX_REGIONS>> = Once::new();
+static INITRD: Once<Option<Initrd>> = Once::new();
+static RESERVED_MEM: Once<heapless::Vec<ReservedMemory, MAX_RESERVED_NODES>> = Once::new();
+static mut VA_OFFSET: usize = 0;
+
+fn va_offset() -> usize {
+    unsafe { VA_OFFSET }
+}
+
+/// The initrd/initramfs image loaded by the bootloader.
+#[derive(Debug, Clone, Copy)]
+pub struct Initrd {
+    start: PhysAddr,
+    size: usize,
+}
+
+impl Initrd {
+    /// Returns the physical range of the image.
+    pub fn phys_range(&self) -> Range<PhysAddr> {
+        self.start..self.start + self.size
+    }
+
+    /// Returns where the image is mapped in the kernel address space.
+    pub fn virt_range(&self) -> Range<VirtAddr> {
+        let start = MemIfImpl::phys_to_virt(self.start);
+        start..start + self.size
+    }
+
+    /// Returns the contents of the image.
+    pub fn as_bytes(&self) -> &'static [u8] {
+        // The image lies in a reserved region, which is mapped but never
+        // handed out by the allocator.
+        unsafe { core::slice::from_raw_parts(self.virt_range().start.as_ptr(), self.size) }
+    }
+}
+
+/// Returns the initrd described by `linux,initrd-start`/`linux,initrd-end` in
+/// `/chosen`, if any.
+pub fn initrd() -> Option<Initrd> {
+    *INITRD.get()?
+}
+
+fn find_initrd() -> Option<Initrd> {
+    let fdt = crate::fdt();
+    let chosen = fdt.find_nodes("/chosen").next()?;
+    let start = prop_uint(&chosen, "linux,initrd-start")? as usize;
+    let end = prop_uint(&chosen, "linux,initrd-end")? as usize;
+    if end <= start {
+        return None;
+    }
+    Some(Initrd {
+        start: PhysAddr::from_usize(start),
+        size: end - start,
+    })
+}
+
+pub fn setup() {
+    unsafe {
+        VA_OFFSET = boot_info().kimage_start_vma as usize - boot_info().kimage_start_lma as usize
+    };
+
+    INITRD.call_once(find_initrd);
+    RESERVED_MEM.call_once(reserved::parse);
+
+    RAM_LIST.call_once(|| {
+        let mut ram_list = RegionList::new("RAM");
+        for one in boot_info()
+            .memory_regions
+            .iter()
+            .filter(|one| matches!(one.kind, MemoryRegionKind::Ram))
+        {
+            ram_list.insert(one.start, one.end - one.start);
+        }
+        if let Some(limit) = cmdline().get_size("mem") {
+            ram_list.truncate_total(limit);
+        }
+        for (start, size) in reserved_memory_pages(true) {
+            ram_list.remove(start, size);
+        }
+        ram_list
+    });
+
+    RESERVED_LIST.call_once(|| {
+        let mut rsv_list = RegionList::new("reserved");
+
+        unsafe extern "C" {
+            fn _skernel();
+        }
+        let head_start = boot_info().kimage_start_lma as usize;
+        rsv_list.insert(
+            head_start,
+            (_skernel as *const () as usize) - va_offset() - head_start,
+        );
+
+        for one in boot_info().memory_regions.iter().filter(|one| {
+            matches!(
+                one.kind,
+                MemoryRegionKind::Reserved | MemoryRegionKind::Bootloader
+            )
+        }) {
+            rsv_list.insert(
+                one.start.align_down_4k(),
+                one.end.align_up_4k() - one.start.align_down_4k(),
+            );
+        }
+
+        if let Some(initrd) = initrd() {
+            let range = initrd.phys_range();
+            let start = range.start.align_down_4k();
+            rsv_list.insert(start.as_usize(), range.end.align_up_4k() - start);
+        }
+
+        for (start, size) in memmap_exclusions() {
+            rsv_list.insert(start, size);
+        }
+
+        for (start, size) in reserved_memory_pages(false) {
+            rsv_list.insert(start, size);
+        }
+        // Bootloaders may report `no-map` regions as reserved too.
+        for (start, size) in reserved_memory_pages(true) {
+            rsv_list.remove(start, size);
+        }
+
+        rsv_list
+    });
+
+    MMIO.call_once(|| {
+        let mut mmio_list = RegionList::new("MMIO");
+        if let Some(debug) = &boot_info().debug_console {
+            mmio_list.insert(debug.base_phys.align_down_4k(), 0x1000);
+        }
+        for &(base, size) in crate::config::devices::MMIO_REGIONS {
+            let start = base.align_down_4k();
+            mmio_list.insert(start, (base + size).align_up_4k() - start);
+        }
+        add_device_mmio(&mut mmio_list);
+
+        mmio_list
+    });
+}
+
+/// Returns the `/reserved-memory` regions with or without `no-map`, rounded
+/// out to whole pages.
+fn reserved_memory_pages(no_map: bool) -> impl Iterator<Item = RawRange> {
+    reserved_memory_regions()
+        .iter()
+        .filter(move |r| r.no_map() == no_map)
+        .map(|r| {
+            let start = r.start().align_down_4k();
+            let end = (r.start() + r.size()).align_up_4k();
+            (start.as_usize(), end - start)
+        })
+}
+
+/// Adds the `reg` windows of every enabled device node to `list`, rounded out
+/// to whole pages.
+///
+/// `reg` is only a CPU physical address if every ancestor has `ranges`; the
+/// children of e.g. I2C or SPI controllers carry bus addresses instead. The
+/// translation through the parents' `ranges` is done by `fdt_parser`.
+fn add_device_mmio(list: &mut RegionList<MAX_REGIONS>) {
+    const MAX_DEPTH: usize = 16;
+
+    let fdt = crate::fdt();
+    let ram = RAM_LIST.wait();
+    // `mapped[level]`: the children of the last node seen at `level` are
+    // memory-mapped.
+    let mut mapped = [false; MAX_DEPTH];
+    for node in fdt.all_nodes() {
+        let level = node.level;
+        if level >= MAX_DEPTH {
+            continue;
+        }
+        if matches!(node.name(), "" | "/") {
+            mapped[level] = true;
+            continue;
+        }
+
+        let parent_mapped = level > 0 && mapped[level - 1];
+        let enabled = !matches!(node.status(), Some(Status::Disabled));
+        // RAM is described by `/memory` and `/reserved-memory`, not devices.
+        let is_ram = matches!(node.name(), "memory" | "reserved-memory")
+            || node.name().starts_with("memory@")
+            || prop_strs(&node, "device_type").any(|t| t == "memory");
+        mapped[level] =
+            parent_mapped && enabled && !is_ram && node.find_property("ranges").is_some();
+
+        if !parent_mapped || !enabled || is_ram {
+            continue;
+        }
+        let Some(regs) = node.reg() else {
+            continue;
+        };
+        for reg in regs {
+            let Some(size) = reg.size.filter(|&s| s > 0) else {
+                continue;
+            };
+            let start = (reg.address as usize).align_down_4k();
+            let end = (reg.address as usize + size).align_up_4k();
+            if ram.iter().any(|&(s, sz)| start < s + sz && s < end) {
+                debug!(
+                    "{}: reg [{start:#x}, {end:#x}) overlaps RAM, not mapped as MMIO",
+                    node.name()
+                );
+                continue;
+            }
+            list.insert(start, end - start);
+        }
+    }
+}
+
+/// Parses the `memmap=<size>$<addr>` ranges of the command line, which are
+/// kept out of the allocator.
+///
+/// Several ranges may be given comma-separated or with repeated `memmap=`.
+/// The `$` may be escaped as `\$`, as bootloader scripts usually require.
+fn memmap_exclusions() -> impl Iterator<Item = RawRange> {
+    cmdline()
+        .get_all("memmap")
+        .flat_map(|v| v.split(','))
+        .filter_map(|one| {
+            let Some((size, addr)) = one.split_once('$') else {
+                warn!("memmap={one}: only <size>$<addr> is supported, ignored");
+                return None;
+            };
+            let size = parse_size(size.trim_end_matches('\\'))?;
+            let start = parse_size(addr)?.align_down_4k();
+            let end = (start + size).align_up_4k();
+            Some((start, end - start))
+        })
+}
+
+/// Returns the total size of usable RAM.
+pub fn ram_total() -> usize {
+    RAM_LIST.wait().total_size()
+}
+
+/// Logs the effective memory layout, including command line overrides.
+pub fn log_layout() {
+    let ram = RAM_LIST.wait();
+    if let Some(limit) = cmdline().get("mem") {
+        info!("RAM limited by mem={limit}");
+    }
+    info!(
+        "RAM: {} MiB in {} range(s)",
+        ram.total_size() >> 20,
+        ram.len()
+    );
+    for &(start, size) in ram.iter() {
+        info!("  RAM      [{:#x}, {:#x})", start, start + size);
+    }
+    for &(start, size) in RESERVED_LIST.wait().iter() {
+        info!("  reserved [{:#x}, {:#x})", start, start + size);
+    }
+    for &(start, size) in MMIO.wait().iter() {
+        debug!("  MMIO     [{:#x}, {:#x})", start, start + size);
+    }
+}
+
+#[impl_plat_interface]
+impl MemIf for MemIfImpl {
+    /// Returns all physical memory (RAM) ranges on the platform.
+    ///
+    /// All memory ranges except reserved ranges (including the kernel loaded
+    /// range) are free for allocation.
+    fn phys_ram_ranges() -> &'static [RawRange] {
+        let ls = RAM_LIST.wait();
+        for range in ls.iter() {
+            trace!("RAM range: {:#x?}", range);
+        }
+
+        ls
+    }
+
+    /// Returns all reserved physical memory ranges on the platform.
+    ///
+    /// Reserved memory can be contained in [`phys_ram_ranges`], they are not
+    /// allocatable but should be mapped to kernel's address space.
+    ///
+    /// Note that the ranges returned should not include the range where the
+    /// kernel is loaded.
+    fn reserved_phys_ram_ranges() -> &'static [RawRange] {
+        RESERVED_LIST.wait()
+    }
+
+    /// Returns all device memory (MMIO) ranges on the platform.
+    fn mmio_ranges() -> &'static [RawRange] {
+        MMIO.wait()
+    }
+
+    fn phys_to_virt(p: PhysAddr) -> VirtAddr {
+        if kimage_range_phys().contains(&p) {
+            VirtAddr::from_usize(p.as_usize() + va_offset())
+        } else {
+            // MMIO or other reserved regions
+            VirtAddr::from_usize(p.as_usize() + KLINER_OFFSET)
+        }
+    }
+    fn virt_to_phys(p: VirtAddr) -> PhysAddr {
+        if (KIMAGE_VADDR..KIMAGE_VADDR + KIMAGE_VSIZE).contains(&p.as_usize()) {
+            PhysAddr::from_usize(p.as_usize() - va_offset())
+        } else {
+            PhysAddr::from_usize(p.as_usize() - KLINER_OFFSET)
+        }
+    }
+
+    fn kernel_aspace() -> (VirtAddr, usize) {
+        #[cfg(feature = "hv")]
+        {
+            (VirtAddr::from_usize(0), 0xffff_ffff_f000)
+        }
+        #[cfg(not(feature = "hv"))]
+        {
+            (
+                VirtAddr::from_usize(0xffff_8000_0000_0000),
+                0x0000_7fff_ffff_f000,
+            )
+        }
+    }
+}
+
+fn kimage_range_phys() -> Range<PhysAddr> {
+    unsafe extern "C" {
+        fn _ekernel();
+    }
+
+    let start = PhysAddr::from_usize(boot_info().kimage_start_lma as usize);
+    let end = PhysAddr::from_usize(_ekernel as *const () as usize - va_offset());
+    start..end
+}
@@ -0,0 +1,108 @@
+use core::ops::Deref;
+
+use axplat::mem::RawRange;
+use heapless::Vec;
+
+/// A sorted list of disjoint physical ranges.
+///
+/// It is filled in `init_early`, before any allocator exists, so the storage
+/// is fixed. Overlapping and adjacent ranges are merged on insertion, and
+/// running out of space panics: a dropped reserved range would be handed to
+/// the allocator, which is far worse than failing to boot.
+pub struct RegionList<const N: usize> {
+    what: &'static str,
+    ranges: Vec<RawRange, N>,
+}
+
+impl<const N: usize> RegionList<N> {
+    pub const fn new(what: &'static str) -> Self {
+        Self {
+            what,
+            ranges: Vec::new(),
+        }
+    }
+
+    fn insert_at(&mut self, idx: usize, range: RawRange) {
+        if self.ranges.insert(idx, range).is_err() {
+            panic!(
+                "too many {} regions (max {N}), cannot record [{:#x}, {:#x})",
+                self.what,
+                range.0,
+                range.0 + range.1
+            );
+        }
+    }
+
+    /// Adds `[start, start + size)`, merging it with the ranges it overlaps
+    /// or touches.
+    pub fn insert(&mut self, start: usize, size: usize) {
+        if size == 0 {
+            return;
+        }
+        let (mut start, mut end) = (start, start + size);
+        let mut idx = 0;
+        while idx < self.ranges.len() {
+            let (s, e) = (self.ranges[idx].0, self.ranges[idx].0 + self.ranges[idx].1);
+            if e < start {
+                idx += 1;
+                continue;
+            }
+            if s > end {
+                break;
+            }
+            start = start.min(s);
+            end = end.max(e);
+            self.ranges.remove(idx);
+        }
+        self.insert_at(idx, (start, end - start));
+    }
+
+    /// Removes `[start, start + size)`, splitting ranges that contain it.
+    pub fn remove(&mut self, start: usize, size: usize) {
+        let end = start + size;
+        let mut idx = 0;
+        while idx < self.ranges.len() {
+            let (s, e) = (self.ranges[idx].0, self.ranges[idx].0 + self.ranges[idx].1);
+            if e <= start {
+                idx += 1;
+                continue;
+            }
+            if s >= end {
+                break;
+            }
+            self.ranges.remove(idx);
+            if s < start {
+                self.insert_at(idx, (s, start - s));
+                idx += 1;
+            }
+            if e > end {
+                self.insert_at(idx, (end, e - end));
+                idx += 1;
+            }
+        }
+    }
+
+    /// Keeps the lowest ranges until `limit` bytes are covered and drops the
+    /// rest.
+    pub fn truncate_total(&mut self, limit: usize) {
+        let mut left = limit;
+        self.ranges.retain_mut(|(_, size)| {
+            *size = (*size).min(left);
+            left -= *size;
+            *size > 0
+        });
+    }
+
+    /// Returns the sum of the sizes of all ranges.
+    pub fn total_size(&self) -> usize {
+        self.ranges.iter().map(|&(_, size)| size).sum()
+    }
+}
+
+impl<const N: usize> Deref for RegionList<N> {
+    type Target = [RawRange];
+
+    fn deref(&self) -> &[RawRange] {
+        &self.ranges
+    }
+}
@@ -0,0 +1,129 @@
+use axplat::mem::PhysAddr;
+use fdt_parser::Status;
+use heapless::Vec;
+use log::{debug, warn};
+
+/// Maximum number of `/reserved-memory` children that are recorded.
+pub(super) const MAX_RESERVED_NODES: usize = 32;
+
+/// A carve-out described by a child of `/reserved-memory`.
+#[derive(Debug, Clone, Copy)]
+pub struct ReservedMemory {
+    name: &'static str,
+    compatible: &'static [u8],
+    start: PhysAddr,
+    size: usize,
+    no_map: bool,
+    reusable: bool,
+}
+
+impl ReservedMemory {
+    /// Returns the node name, including the unit address.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Iterates over the entries of the node's `compatible`.
+    pub fn compatibles(&self) -> impl Iterator<Item = &'static str> {
+        self.compatible
+            .split(|&b| b == 0)
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| core::str::from_utf8(s).ok())
+    }
+
+    /// Returns the physical start address.
+    pub fn start(&self) -> PhysAddr {
+        self.start
+    }
+
+    /// Returns the size in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns `true` if the region has `no-map`, i.e. it is not part of the
+    /// kernel's linear mapping.
+    pub fn no_map(&self) -> bool {
+        self.no_map
+    }
+
+    /// Returns `true` if the region has `reusable`. Such regions are still
+    /// kept away from the allocator, as nothing here can reclaim them.
+    pub fn reusable(&self) -> bool {
+        self.reusable
+    }
+
+    fn matches(&self, key: &str) -> bool {
+        self.name == key
+            || self.name.split('@').next() == Some(key)
+            || self.compatibles().any(|c| c == key)
+    }
+}
+
+/// Parses the children of `/reserved-memory`.
+///
+/// Children that are only sized for dynamic allocation (`size` without `reg`)
+/// are skipped, as there is nobody to place them.
+pub(super) fn parse() -> Vec<ReservedMemory, MAX_RESERVED_NODES> {
+    let mut list = Vec::new();
+    let fdt = crate::fdt();
+    let mut parent_level = None;
+    for node in fdt.all_nodes() {
+        match parent_level {
+            None if node.name() == "reserved-memory" => {
+                parent_level = Some(node.level);
+                continue;
+            }
+            Some(level) if node.level <= level => break,
+            Some(level) if node.level == level + 1 => {}
+            _ => continue,
+        }
+        if matches!(node.status(), Some(Status::Disabled)) {
+            continue;
+        }
+        let Some(reg) = node.reg().and_then(|mut regs| regs.next()) else {
+            warn!(
+                "reserved-memory/{}: dynamic allocation is not supported, ignored",
+                node.name()
+            );
+            continue;
+        };
+        let region = ReservedMemory {
+            name: node.name(),
+            compatible: node
+                .find_property("compatible")
+                .map(|p| p.raw_value())
+                .unwrap_or_default(),
+            start: PhysAddr::from_usize(reg.address as usize),
+            size: reg.size.unwrap_or_default(),
+            no_map: node.find_property("no-map").is_some(),
+            reusable: node.find_property("reusable").is_some(),
+        };
+        debug!(
+            "reserved-memory/{}: [{:#x}, {:#x}){}{}",
+            region.name,
+            region.start,
+            region.start + region.size,
+            if region.no_map { " no-map" } else { "" },
+            if region.reusable { " reusable" } else { "" },
+        );
+        if list.push(region).is_err() {
+            panic!("too many /reserved-memory nodes (max {MAX_RESERVED_NODES})");
+        }
+    }
+    list
+}
+
+/// Looks up a `/reserved-memory` region by node name, with or without the
+/// unit address, or by compatible, e.g. `"shared-dma-pool"` or `"ramoops"`.
+pub fn reserved_memory(key: &str) -> Option<ReservedMemory> {
+    reserved_memory_regions()
+        .iter()
+        .find(|r| r.matches(key))
+        .copied()
+}
+
+/// Returns all regions described under `/reserved-memory`.
+pub fn reserved_memory_regions() -> &'static [ReservedMemory] {
+    super::RESERVED_MEM.get().map_or(&[], |v| v.as_slice())
+}
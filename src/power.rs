@@ -1,5 +1,5 @@
 use axplat::power::PowerIf;
-use log::info;
+use log::{error, info};
 
 struct PowerImpl;
 
@@ -10,10 +10,20 @@ impl PowerIf for PowerImpl {
     ///
     /// Where `cpu_id` is the logical CPU ID (0, 1, ..., N-1, N is the number of
     /// CPU cores on the platform).
+    ///
+    /// CPUs at or beyond [`cpu_num`](crate::cpu_num) are not started.
     #[cfg(feature = "smp")]
     fn cpu_boot(cpu_idx: usize, stack_top_paddr: usize) {
         use axcpu::asm::{disable_irqs, irqs_enabled};
 
+        if cpu_idx >= crate::smp::cpu_count() {
+            error!(
+                "CPU{cpu_idx} is not available (maxcpus or device tree), not booting it; \
+                 waiting for it will hang, see `cpu_num()`"
+            );
+            return;
+        }
+
         let irq = irqs_enabled();
         disable_irqs();
         let cpu_id = crate::smp::cpu_idx_to_id(cpu_idx);
@@ -27,6 +37,7 @@ impl PowerIf for PowerImpl {
 
     /// Shutdown the whole system.
     fn system_off() -> ! {
+        crate::console::flush();
         somehal::power::shutdown()
     }
 }
@@ -1,10 +1,10 @@
 use alloc::vec::Vec;
 use fdt_parser::Status;
-use log::debug;
+use log::{debug, error, info};
 use somehal::boot_info;
 use spin::Once;
 
-use crate::fdt;
+use crate::{cmdline::cmdline, config, fdt};
 
 static CPU_ID_LIST: Once<Vec<usize>> = Once::new();
 static mut PHYS_VIRT_OFFSET: usize = 0;
@@ -21,6 +21,21 @@ pub fn init() {
                 ls.push(cpu_id);
             }
         }
+        // The boot CPU is always kept, so `maxcpus=0` acts like `maxcpus=1`.
+        if let Some(max) = cmdline().get_usize("maxcpus").map(|n| n.max(1))
+            && max < ls.len()
+        {
+            info!("maxcpus: bringing up {max} of {} CPUs", ls.len());
+            ls.truncate(max);
+        }
+        if ls.len() < config::plat::CPU_NUM {
+            error!(
+                "only {} CPUs available but built for {}: a kernel waiting for \
+                 `plat.cpu-num` CPUs hangs, it should use `cpu_num()` instead",
+                ls.len(),
+                config::plat::CPU_NUM
+            );
+        }
         ls
     });
 
@@ -50,6 +65,11 @@ fn cpu_id_list() -> Vec<usize> {
         .collect()
 }
 
+/// Returns the number of CPUs that may be brought up.
+pub fn cpu_count() -> usize {
+    CPU_ID_LIST.wait().len()
+}
+
 pub fn cpu_idx_to_id(cpu_idx: usize) -> usize {
     let cpu_id_list = CPU_ID_LIST.wait();
     if cpu_idx < cpu_id_list.len() {
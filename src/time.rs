@@ -59,6 +59,15 @@ impl TimeIf for TimeIfImpl {
     }
 }
 
+/// Returns the timer interrupt, once the timer has been probed.
+pub fn irq() -> Option<usize> {
+    if cfg!(feature = "irq") {
+        TIMER_IRQ_CONFIG.get().map(|c| c.irq.into())
+    } else {
+        None
+    }
+}
+
 fn set_tval(tval: u64) {
     #[cfg(feature = "hv")]
     unsafe {